@@ -0,0 +1,58 @@
+use crate::log_entry::{LogEntry, RecordKind};
+
+/// A group of puts and deletes applied atomically by [`KvStore::write`](crate::KvStore::write)
+/// or [`KvsEngine::write`](crate::KvsEngine::write).
+///
+/// Either every operation in the batch survives a crash or none does, and
+/// readers never see the batch half applied. Operations apply in the order
+/// they were added, so a later put or delete of the same key wins.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    entries: Vec<LogEntry>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.entries.push(LogEntry::value(key.as_ref().to_vec(), value.as_ref().to_vec()));
+        self
+    }
+
+    /// Removes a key. Removing a key that does not exist is not an error.
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.entries.push(LogEntry::tombstone(key.as_ref().to_vec()));
+        self
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the batch holds no operations.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes every operation from the batch.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn into_entries(self) -> Vec<LogEntry> {
+        self.entries
+    }
+
+    /// The operations in the order they were added, `None` for a delete
+    pub(crate) fn into_ops(self) -> impl Iterator<Item = (Vec<u8>, Option<Vec<u8>>)> {
+        self.entries.into_iter().map(|e| match e.kind {
+            RecordKind::Tombstone => (e.key, None),
+            _ => (e.key, Some(e.value)),
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use clap::Parser;
+
+use kvs::{ClientCli, Command, KvError, KvsClient, Result};
+
+fn main() -> Result<()> {
+    let cli = ClientCli::parse();
+    let mut client = KvsClient::connect(cli.addr)?;
+    match cli.command {
+        Command::Get(args) => {
+            match client.get(args.key)? {
+                Some(v) => {
+                    println!("{}", v)
+                }
+                None => {
+                    println!("Key not found")
+                }
+            }
+        }
+        Command::Set(args) => match args.ttl {
+            Some(ttl) => client.set_with_ttl(args.key, args.value, ttl)?,
+            None => client.set(args.key, args.value)?,
+        },
+        Command::Remove(args) => {
+            if let Err(e) = client.remove(args.key) {
+                println!("{}", e);
+                return Err(e);
+            }
+        }
+        Command::Scan(args) => {
+            for (key, value) in client.scan(args.into())? {
+                println!("{}\t{}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+            }
+        }
+        Command::Ttl(args) => {
+            match client.ttl(args.key) {
+                Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(e) if matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)) => {
+                    println!("Key not found")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Command::Cas(args) => {
+            let res = client.compare_and_swap(args.key, args.expected.as_deref().map(str::as_bytes),
+                                              args.new.as_deref().map(str::as_bytes))?;
+            if let Err(mismatch) = res {
+                match mismatch.current {
+                    Some(v) => println!("{}", String::from_utf8_lossy(&v)),
+                    None => println!("Key not found"),
+                }
+                return Err(anyhow::anyhow!("Value did not match"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use clap::Parser;
+
+use kvs::{Engine, KvStore, KvsEngine, KvsServer, Result, ServerCli};
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = ServerCli::parse();
+    let pwd = std::env::current_dir()?;
+    match cli.engine {
+        Engine::Kvs => run(KvStore::open(&pwd)?, cli),
+        #[cfg(feature = "sled")]
+        Engine::Sled => run(kvs::SledEngine::open(&pwd)?, cli),
+    }
+}
+
+fn run<E: KvsEngine>(engine: E, cli: ServerCli) -> Result<()> {
+    log::info!("kvs-server {} using engine {:?}", env!("CARGO_PKG_VERSION"), cli.engine);
+    KvsServer::with_protocol(engine, cli.protocol).run(cli.addr)
+}
@@ -1,14 +1,40 @@
 use clap::Parser;
 
-use kvs::{KvStore, Result};
-use kvs::{Cli, Command};
+use kvs::{KvError, KvStore, KvsEngine, Result, ScanQuery, FORMAT_VERSION};
+use kvs::{Cli, Command, Engine, LocalCommand};
 
 fn main() -> Result<()> {
-    let pwd = std::env::current_dir().unwrap();
-    let mut kvs = KvStore::open(&pwd)?;
     let cli = Cli::parse();
-    match cli.command {
-        Some(Command::Get(args)) => {
+    let command = match cli.command {
+        Some(command) => command,
+        None => {
+            panic!("unimplemented")
+        }
+    };
+    let pwd = std::env::current_dir().unwrap();
+    let command = match command {
+        LocalCommand::Store(command) => command,
+        LocalCommand::Upgrade => {
+            if cli.engine != Engine::Kvs {
+                return Err(anyhow::anyhow!(KvError::Unsupported("upgrade")));
+            }
+            match KvStore::upgrade(&pwd)? {
+                0 => println!("Store is already at format version {}", FORMAT_VERSION),
+                n => println!("Upgraded {} datafiles to format version {}", n, FORMAT_VERSION),
+            }
+            return Ok(());
+        }
+    };
+    match cli.engine {
+        Engine::Kvs => run(KvStore::open(&pwd)?, command),
+        #[cfg(feature = "sled")]
+        Engine::Sled => run(kvs::SledEngine::open(&pwd)?, command),
+    }
+}
+
+fn run<E: KvsEngine>(kvs: E, command: Command) -> Result<()> {
+    match command {
+        Command::Get(args) => {
             match kvs.get(args.key)? {
                 Some(v) => {
                     println!("{}", v)
@@ -18,10 +44,11 @@ fn main() -> Result<()> {
                 }
             }
         }
-        Some(Command::Set(args)) => {
-            kvs.set(args.key, args.value)?;
-        }
-        Some(Command::Remove(args)) => {
+        Command::Set(args) => match args.ttl {
+            Some(ttl) => kvs.set_with_ttl(args.key, args.value, ttl)?,
+            None => kvs.set(args.key, args.value)?,
+        },
+        Command::Remove(args) => {
             return match kvs.remove(args.key) {
                 Ok(_) => {
                     Ok(())
@@ -32,9 +59,33 @@ fn main() -> Result<()> {
                 }
             }
         }
-        None => {
-            panic!("unimplemented")
+        Command::Scan(args) => {
+            for (key, value) in ScanQuery::from(args).run(&kvs)? {
+                println!("{}\t{}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+            }
+        }
+        Command::Ttl(args) => {
+            match kvs.ttl(args.key) {
+                Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(e) if matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)) => {
+                    println!("Key not found")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Command::Cas(args) => {
+            let res = kvs.compare_and_swap(args.key, args.expected.as_deref().map(str::as_bytes),
+                                           args.new.as_deref().map(str::as_bytes))?;
+            if let Err(mismatch) = res {
+                match mismatch.current {
+                    Some(v) => println!("{}", String::from_utf8_lossy(&v)),
+                    None => println!("Key not found"),
+                }
+                return Err(anyhow::anyhow!("Value did not match"));
+            }
         }
     }
     Ok(())
 }
+
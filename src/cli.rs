@@ -1,4 +1,9 @@
-use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::protocol::{ScanQuery, DEFAULT_ADDR};
 
 /// Command line interface struct.
 #[derive(Parser)]
@@ -6,7 +11,58 @@ use clap::{Args, Parser, Subcommand};
 pub struct Cli {
     /// The command to run.
     #[command(subcommand)]
-    pub command: Option<Command>,
+    pub command: Option<LocalCommand>,
+    /// The storage engine to use.
+    #[arg(long, value_enum, default_value_t = Engine::Kvs, global = true)]
+    pub engine: Engine,
+}
+
+/// Command line interface of `kvs-client`.
+#[derive(Parser)]
+#[command(author, version, about = "Client for a kvs-server")]
+pub struct ClientCli {
+    /// The command to send.
+    #[command(subcommand)]
+    pub command: Command,
+    /// Address of the server.
+    #[arg(long, default_value = DEFAULT_ADDR, global = true)]
+    pub addr: SocketAddr,
+}
+
+/// Command line interface of `kvs-server`.
+#[derive(Parser)]
+#[command(author, version, about = "Serves a key-value store over TCP")]
+pub struct ServerCli {
+    /// Address to listen on.
+    #[arg(long, default_value = DEFAULT_ADDR)]
+    pub addr: SocketAddr,
+    /// The storage engine to use.
+    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+    pub engine: Engine,
+    /// The wire protocol to speak.
+    #[arg(long, value_enum, default_value_t = Protocol::Kvs)]
+    pub protocol: Protocol,
+}
+
+/// Enum representing the protocols `kvs-server` can speak.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// The length-prefixed protocol used by `kvs-client`
+    Kvs,
+    /// Redis RESP2, for `redis-cli` and Redis client libraries
+    Resp,
+    /// A JSON API over HTTP/1.1
+    Http,
+}
+
+/// Enum representing the available storage engines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    /// The built-in log-structured store
+    Kvs,
+    /// The sled embedded database
+    #[cfg(feature = "sled")]
+    Sled,
 }
 
 /// Enum representing the possible commands.
@@ -19,6 +75,22 @@ pub enum Command {
     /// Removes a given key
     #[clap(name = "rm")]
     Remove(RemoveArgs),
+    /// Lists key-value pairs in key order
+    Scan(ScanArgs),
+    /// Sets or removes a key only if it has the expected value
+    Cas(CasArgs),
+    /// Shows how long a key has left before it expires
+    Ttl(TtlArgs),
+}
+
+/// Commands of `kvs`, which works on the store in the current directory.
+#[derive(Subcommand)]
+pub enum LocalCommand {
+    /// A command on the keys of the store
+    #[command(flatten)]
+    Store(Command),
+    /// Migrates the store to the current on-disk format
+    Upgrade,
 }
 
 /// Struct representing the arguments for the get command.
@@ -35,6 +107,9 @@ pub struct SetArgs {
     pub key: String,
     /// The value.
     pub value: String,
+    /// Expire the key after this long, such as 500ms, 30s, 10m, 2h or 1d.
+    #[arg(long, value_parser = parse_duration)]
+    pub ttl: Option<Duration>,
 }
 
 /// Struct representing the arguments for the remove command.
@@ -43,3 +118,87 @@ pub struct RemoveArgs {
     /// The key.
     pub key: String,
 }
+
+/// Struct representing the arguments for the scan command.
+#[derive(Args)]
+pub struct ScanArgs {
+    /// First key to list.
+    pub start: Option<String>,
+    /// Key to stop before.
+    pub end: Option<String>,
+    /// Only list keys starting with this prefix.
+    #[arg(long)]
+    pub prefix: Option<String>,
+    /// List in reverse key order.
+    #[arg(long)]
+    pub reverse: bool,
+    /// List at most this many pairs.
+    #[arg(long)]
+    pub limit: Option<u64>,
+}
+
+impl From<ScanArgs> for ScanQuery {
+    fn from(args: ScanArgs) -> Self {
+        ScanQuery {
+            start: args.start.map(String::into_bytes),
+            end: args.end.map(String::into_bytes),
+            prefix: args.prefix.map(String::into_bytes),
+            reverse: args.reverse,
+            limit: args.limit,
+        }
+    }
+}
+
+/// Struct representing the arguments for the cas command.
+#[derive(Args)]
+pub struct CasArgs {
+    /// The key.
+    pub key: String,
+    /// The value the key must have. The key must not exist if omitted.
+    #[arg(long)]
+    pub expected: Option<String>,
+    /// The value to set. The key is removed if omitted.
+    #[arg(long)]
+    pub new: Option<String>,
+}
+
+/// Struct representing the arguments for the ttl command.
+#[derive(Args)]
+pub struct TtlArgs {
+    /// The key.
+    pub key: String,
+}
+
+/// Parses a duration given as a number followed by ms, s, m, h or d. A bare number is seconds.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("unknown unit '{}' in duration '{}'", unit, s)),
+    };
+    n.checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration '{}' is too long", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+}
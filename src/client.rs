@@ -0,0 +1,104 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::engines;
+use crate::protocol::{self, Request, Response, ScanQuery};
+use crate::{CasResult, Result};
+
+/// A connection to a `kvs-server`.
+///
+/// Errors raised by the server come back as the same errors, so a missing key
+/// is still a [`KvError::KeyNotFound`](crate::KvError::KeyNotFound).
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(engines::to_string).transpose()
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Removes a key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key)
+    }
+
+    /// Gets the value of a key as bytes, or `None` if the key does not exist.
+    pub fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.send(&Request::Get { key: key.as_ref().to_vec() })?.into_value()
+    }
+
+    /// Sets the value of a key. Keys and values can be any bytes.
+    pub fn set_bytes(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let request = Request::Set { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() };
+        self.send(&request)?.into_value().map(|_| ())
+    }
+
+    /// Removes a key.
+    pub fn remove_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.send(&Request::Remove { key: key.as_ref().to_vec() })?.into_value().map(|_| ())
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let request = Request::SetWithTtl {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            ttl_millis: ttl.as_millis() as u64,
+        };
+        self.send(&request)?.into_value().map(|_| ())
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if it never does.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.send(&Request::Ttl { key: key.into_bytes() })?.into_ttl()
+    }
+
+    /// Replaces the value of a key if it currently is `expected`.
+    /// See [`KvsEngine::compare_and_swap`](crate::KvsEngine::compare_and_swap).
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult> {
+        let request = Request::CompareAndSwap {
+            key: key.as_ref().to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.map(<[u8]>::to_vec),
+        };
+        self.send(&request)?.into_cas_result()
+    }
+
+    /// Lists key-value pairs in key order.
+    pub fn scan(&mut self, query: ScanQuery) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.send(&Request::Scan(query))?.into_pairs()
+    }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        protocol::write_message(&mut self.writer, request)?;
+        match protocol::read_message::<Response>(&mut self.reader)? {
+            Some(response) => Ok(response),
+            None => Err(anyhow!("Server closed the connection")),
+        }
+    }
+}
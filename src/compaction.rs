@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, warn};
+
+use crate::datafile::DataFile;
+use crate::hint::{self, HintEntry};
+use crate::index::{KeyDir, Location};
+use crate::log_entry::{self, LogEntry};
+use crate::merge;
+use crate::kv::Shared;
+use crate::Result;
+
+/// Work handed to the compaction worker
+pub enum Job {
+    /// Datafile `id` was sealed. `output_id` is the free id reserved right after it.
+    Sealed { id: u64, output_id: u64 },
+    Shutdown,
+}
+
+/// Handle to the background thread that writes hint files and merges sealed datafiles.
+/// Dropping it waits for the job in progress to finish.
+pub struct Compactor {
+    sender: Sender<Job>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn spawn(shared: Arc<Shared>) -> Result<Compactor> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_string())
+            .spawn(move || run(shared, receiver))?;
+        Ok(Compactor {
+            sender,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn submit(&self, job: Job) {
+        // The worker only goes away on shutdown, so a failed send can be ignored
+        let _ = self.sender.send(job);
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.submit(Job::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(shared: Arc<Shared>, receiver: Receiver<Job>) {
+    for job in receiver {
+        match job {
+            Job::Sealed { id, output_id } => {
+                if let Err(e) = write_hint(&shared, id) {
+                    error!("Failed to write hint file for datafile {}: {}", id, e);
+                }
+                if !should_compact(&shared) {
+                    continue;
+                }
+                if let Err(e) = compact(&shared, output_id) {
+                    error!("Compaction into datafile {} failed: {}", output_id, e);
+                }
+            }
+            Job::Shutdown => break,
+        }
+    }
+}
+
+fn write_hint(shared: &Shared, id: u64) -> Result<()> {
+    // Keeps a concurrent compaction from deleting the datafile under us
+    let _guard = shared.compaction_lock.lock().unwrap();
+    let datafile = shared.sealed.read().unwrap().get(&id).cloned();
+    match datafile {
+        Some(df) => hint::write(&shared.path, id, &hint::build(&df)?),
+        None => Ok(()),
+    }
+}
+
+/// Checks the sealed datafiles against the compaction thresholds in the store options
+fn should_compact(shared: &Shared) -> bool {
+    let options = &shared.options;
+    let sealed = shared.sealed.read().unwrap();
+    if sealed.len() > options.compaction_max_segments {
+        return true;
+    }
+    let total: u64 = sealed.values().map(|df| df.size().unwrap_or_default()).sum();
+    let dead = dead_bytes(&shared.key_dir.read().unwrap(), &sealed);
+    total > 0
+        && dead >= options.compaction_min_dead_bytes
+        && dead as f64 / total as f64 >= options.compaction_dead_ratio
+}
+
+/// Whether merging the sealed datafiles would reclaim any space
+pub fn has_dead_bytes(shared: &Shared) -> bool {
+    let sealed = shared.sealed.read().unwrap();
+    dead_bytes(&shared.key_dir.read().unwrap(), &sealed) > 0
+}
+
+/// Bytes in `sealed` that a merge would reclaim: records the index no longer
+/// points at, and the records of keys that have expired
+fn dead_bytes(key_dir: &KeyDir, sealed: &BTreeMap<u64, Arc<DataFile>>) -> u64 {
+    let expired = key_dir.expired_bytes(log_entry::now_millis());
+    sealed.keys()
+        .map(|id| key_dir.dead_bytes(*id) + expired.get(id).copied().unwrap_or_default())
+        .sum()
+}
+
+/// Merges every sealed datafile below `output_id` into datafile `output_id`,
+/// keeping only the values the index still points at.
+///
+/// Tombstones are dropped: every datafile older than the output is an input,
+/// so no older value is left on disk for them to shadow. Merge operands in the
+/// inputs are folded into the value they follow, or copied as they are if the
+/// merge operator fails on them. Expired keys are dropped unless some of their
+/// records are newer than the inputs.
+///
+/// Foreground reads and writes carry on while values are copied. Keys written
+/// in the meantime already point at the active datafile and are left alone when
+/// the index is switched over. Inputs are deleted oldest first, each once no
+/// reader holds it any longer, so a crash part way never resurrects a removed key.
+pub fn compact(shared: &Shared, output_id: u64) -> Result<()> {
+    let _guard = shared.compaction_lock.lock().unwrap();
+    let inputs: BTreeMap<u64, Arc<DataFile>> = {
+        let sealed = shared.sealed.read().unwrap();
+        if sealed.contains_key(&output_id) {
+            return Ok(());
+        }
+        sealed.range(..output_id)
+            .map(|(id, df)| (*id, df.clone()))
+            .collect()
+    };
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    let live: Vec<_> = shared.key_dir.read().unwrap()
+        .iter()
+        .filter(|(_, e)| inputs.contains_key(&e.file_id))
+        .map(|(key, e)| (key.to_owned(), e.clone()))
+        .collect();
+
+    // The output stays under a temporary name until it is complete and synced,
+    // so an interrupted merge leaves the inputs as the only copy of the data
+    let mut output = DataFile::create_temp(&shared.path, output_id)?;
+    let mut hints = Vec::with_capacity(live.len());
+    // Number of records of each key that were copied and where the copies went,
+    // nowhere for a dropped key
+    let mut moved = Vec::with_capacity(live.len());
+    let now = log_entry::now_millis();
+    for (key, e) in &live {
+        if e.is_expired(now) && e.records().all(|location| inputs.contains_key(&location.file_id)) {
+            moved.push((e.operands.len() + 1, Vec::new()));
+            continue;
+        }
+        // Records are in log order, so the ones in the inputs come before any in the active datafile
+        let mut records = Vec::new();
+        for location in e.records().take_while(|location| inputs.contains_key(&location.file_id)) {
+            let value = inputs[&location.file_id].read(location.value_offset, key, location.value_sz)?;
+            records.push(LogEntry { kind: location.kind, key: key.clone(), value });
+        }
+        let copied = records.len();
+        let entries = match fold(shared, key, &records) {
+            Ok(value) => match e.expires_at {
+                Some(expires_at) => vec![LogEntry::expiring(key.clone(), value, expires_at)],
+                None => vec![LogEntry::value(key.clone(), value)],
+            },
+            Err(e) => {
+                warn!("Copying {} records of a key unmerged: {}", copied, e);
+                records
+            }
+        };
+        let mut locations = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let location = Location {
+                kind: entry.kind,
+                file_id: output_id,
+                value_offset: output.write_entry(&entry)?,
+                value_sz: entry.value_size(),
+            };
+            hints.push(HintEntry {
+                kind: location.kind,
+                key: key.clone(),
+                file_id: output_id,
+                value_offset: location.value_offset,
+                value_sz: location.value_sz,
+                expires_at: entry.expires_at(),
+            });
+            locations.push(location);
+        }
+        moved.push((copied, locations));
+    }
+    output.commit()?;
+    hint::write(&shared.path, output_id, &hints)?;
+
+    // Publish the output before any key points at it
+    shared.sealed.write().unwrap().insert(output_id, Arc::new(output));
+    {
+        let mut key_dir = shared.key_dir.write().unwrap();
+        for ((key, old), (copied, locations)) in live.into_iter().zip(moved) {
+            if locations.is_empty() {
+                // Expired, unless written again since
+                if key_dir.get(&key).as_ref() == Some(&old) {
+                    key_dir.remove_key(&key);
+                }
+            } else if !key_dir.relocate(&key, &old, copied, &locations) {
+                // Overwritten or removed while the merge ran, the copy is dead already
+                for location in locations {
+                    key_dir.add_dead_bytes(output_id, location.record_size(&key));
+                }
+            }
+        }
+        for id in inputs.keys() {
+            key_dir.forget_file(*id);
+        }
+    }
+    {
+        let mut sealed = shared.sealed.write().unwrap();
+        for id in inputs.keys() {
+            sealed.remove(id);
+        }
+    }
+    for (id, df) in inputs {
+        wait_for_readers(df).remove()?;
+        hint::remove(&shared.path, id)?;
+    }
+    Ok(())
+}
+
+/// Folds the records of a key copied from the inputs into a single value
+fn fold(shared: &Shared, key: &[u8], records: &[LogEntry]) -> Result<Vec<u8>> {
+    let mut value = None;
+    for record in records {
+        value = Some(merge::apply(shared.merge_operator(), record.kind, key, value, record.value.clone())?);
+    }
+    Ok(value.unwrap_or_default())
+}
+
+/// Waits until `datafile` is no longer shared with a reader and takes it back
+fn wait_for_readers(mut datafile: Arc<DataFile>) -> DataFile {
+    loop {
+        match Arc::try_unwrap(datafile) {
+            Ok(df) => return df,
+            Err(df) => {
+                datafile = df;
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
@@ -1,183 +1,571 @@
-use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::__private::kind::TraitKind;
 use anyhow::anyhow;
+use log::warn;
 
 use crate::LogEntry;
+use crate::log_entry::{self, RecordKind, CRC_SIZE, FRAME_SIZE};
 use crate::Result;
 use crate::error::DataFileError;
+use crate::hint;
+
+/// Extension of every datafile segment
+const DATAFILE_EXT: &str = "dat";
+/// Extension added to files that are still being written and not yet part of the store
+pub const TEMP_EXT: &str = "tmp";
+/// Name of the single datafile written before segments were introduced
+const LEGACY_DATAFILE: &str = "main.dat";
+
+/// Bytes every datafile starts with
+const MAGIC: [u8; 4] = *b"KVSD";
+/// Size of the header at the start of every datafile
+pub const HEADER_SIZE: u64 = 32;
+/// Format version of the datafiles this build reads and writes
+pub const FORMAT_VERSION: u32 = 2;
+/// Format version of datafiles written before they had a header
+pub const HEADERLESS_VERSION: u32 = 1;
+
+/*
+* Header Format :
+* magic | version | file id | created at | reserved | crc
+* [u8; 4] | u32 | u64 | u64 | [u8; 4] | u32
+* Integers are little endian, the creation time is in milliseconds since the
+* Unix epoch and crc is the crc32 of everything before it.
+* Records follow right after the header.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u32,
+    pub file_id: u64,
+    pub created_at: u64,
+}
+
+impl Header {
+    fn new(file_id: u64) -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            file_id,
+            created_at: log_entry::now_millis(),
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut buf = [0u8; HEADER_SIZE as usize];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..8].copy_from_slice(&self.version.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.file_id.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.created_at.to_le_bytes());
+        let crc = crc32fast::hash(&buf[..28]);
+        buf[28..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a header, `None` if it does not match its checksum
+    fn decode(buf: &[u8; HEADER_SIZE as usize]) -> Option<Header> {
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        let crc = u32::from_le_bytes(buf[28..].try_into().unwrap());
+        if buf[..4] != MAGIC || crc32fast::hash(&buf[..28]) != crc {
+            return None;
+        }
+        Some(Header {
+            version: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            file_id: u64_at(8),
+            created_at: u64_at(16),
+        })
+    }
+}
+
+/// Reads the header of datafile `file_id`.
+///
+/// A datafile that does not start with the magic number predates headers and
+/// reports [`HEADERLESS_VERSION`]. Returns `None` if the datafile has no header
+/// yet: it is empty, or a crash cut its header short before any record was written.
+pub fn read_header(f: &File, file_id: u64) -> Result<Option<Header>> {
+    let mut buf = [0u8; HEADER_SIZE as usize];
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read_at(&mut buf[n..], n as u64)? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    if n < buf.len() && MAGIC.starts_with(&buf[..n.min(MAGIC.len())]) {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Ok(Some(Header { version: HEADERLESS_VERSION, file_id, created_at: 0 }));
+    }
+    match Header::decode(&buf) {
+        Some(header) => Ok(Some(header)),
+        None => Err(anyhow!(DataFileError::Corrupted { file_id, offset: 0 })),
+    }
+}
+
+/// Fails with [`DataFileError::UnsupportedVersion`] unless the datafile is in
+/// [`FORMAT_VERSION`] or has no header yet
+fn check_version(f: &File, file_id: u64) -> Result<()> {
+    match read_header(f, file_id)? {
+        Some(header) if header.version != FORMAT_VERSION => {
+            Err(anyhow!(DataFileError::UnsupportedVersion { file_id, version: header.version }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rewrites datafile `id` in `dir` in [`FORMAT_VERSION`] if it is in an older one.
+/// Returns whether it was rewritten.
+///
+/// Every record is decoded and written again as a framed, checksummed record.
+/// The rewrite goes through a temporary file renamed over the datafile, so an
+/// interrupted upgrade leaves either version behind and can be run again.
+pub fn upgrade(dir: &Path, id: u64) -> Result<bool> {
+    let path = dir.join(datafile_name(id));
+    let f = File::open(&path)?;
+    match read_header(&f, id)? {
+        Some(header) if header.version == HEADERLESS_VERSION => {}
+        Some(header) if header.version != FORMAT_VERSION => {
+            return Err(anyhow!(DataFileError::UnsupportedVersion { file_id: id, version: header.version }));
+        }
+        _ => return Ok(false),
+    }
+    // Records move past the header, so the offsets in the hint file go stale
+    hint::remove(dir, id)?;
+    let len = f.metadata()?.len();
+    let mut reader = BufReader::new(f);
+    write_atomic(&path, |out| {
+        let mut out = BufWriter::new(out);
+        out.write_all(&Header::new(id).encode())?;
+        let mut offset = 0;
+        while offset < len {
+            match read_headerless_entry(&mut reader, len - offset)? {
+                Some((le, size)) => {
+                    out.write_all(&le.encode()?)?;
+                    offset += size;
+                }
+                None => {
+                    warn!("Dropping partially written record in datafile {} at offset {}", id, offset);
+                    break;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(())
+    })?;
+    Ok(true)
+}
+
+/*
+* Headerless Record Format :
+* ksz | key | vsz | value
+* u64 | vec<u8> | u64 | vec<u8>
+* Records have no frame, checksum or kind. An empty value marks a deleted key.
+*/
+/// Reads a record of a [`HEADERLESS_VERSION`] datafile with `remaining` bytes
+/// left, along with its size. Returns `None` if the file ends partway through it.
+fn read_headerless_entry(reader: &mut impl Read, remaining: u64) -> Result<Option<(LogEntry, u64)>> {
+    let mut size = 0;
+    let mut fields = [Vec::new(), Vec::new()];
+    for field in fields.iter_mut() {
+        if remaining - size < 8 {
+            return Ok(None);
+        }
+        let mut len = [0u8; 8];
+        reader.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        size += 8;
+        if len > remaining - size {
+            return Ok(None);
+        }
+        field.resize(len as usize, 0);
+        reader.read_exact(field)?;
+        size += len;
+    }
+    let [key, value] = fields;
+    let le = if value.is_empty() {
+        LogEntry::tombstone(key)
+    } else {
+        LogEntry::value(key, value)
+    };
+    Ok(Some((le, size)))
+}
+
+/// Returns the file name of the datafile with the given id.
+/// Ids are zero padded so that segments sort by name in creation order.
+pub fn datafile_name(id: u64) -> String {
+    format!("{:020}.{}", id, DATAFILE_EXT)
+}
+
+/// Lists the ids of all datafiles in `dir`, oldest first.
+pub fn list_datafiles(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(DATAFILE_EXT) {
+            continue;
+        }
+        if let Some(id) = path.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(TEMP_EXT);
+    path.with_file_name(name)
+}
+
+/// Writes `path` atomically: `write` fills a temporary file, which is then
+/// fsynced and renamed over `path`
+pub fn write_atomic(path: &Path, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    let temp = temp_path(path);
+    let mut f = File::create(&temp)?;
+    write(&mut f)?;
+    f.sync_all()?;
+    drop(f);
+    std::fs::rename(&temp, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Fsyncs a directory so renames and new files in it are durable
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Deletes temporary files left behind by writes that never completed
+pub fn remove_temp_files(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|e| e.to_str()) == Some(TEMP_EXT) {
+            warn!("Removing leftover temporary file {}", path.display());
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fails with [`DataFileError::LegacyDatafile`] if `dir` holds a pre-segment `main.dat`
+pub fn check_legacy_datafile(dir: &Path) -> Result<()> {
+    if dir.join(LEGACY_DATAFILE).is_file() {
+        return Err(anyhow!(DataFileError::LegacyDatafile));
+    }
+    Ok(())
+}
+
+/// Renames a pre-segment `main.dat` to the first segment so it can be upgraded.
+pub fn migrate_legacy_datafile(dir: &Path) -> Result<()> {
+    let legacy = dir.join(LEGACY_DATAFILE);
+    if !legacy.is_file() {
+        return Ok(());
+    }
+    if !list_datafiles(dir)?.is_empty() {
+        return Err(anyhow!("{} sits next to datafile segments, move one or the other out of the store",
+                           legacy.display()));
+    }
+    std::fs::rename(&legacy, dir.join(datafile_name(1)))?;
+    sync_dir(dir)
+}
 
+/// A single segment of the log.
+/// The active segment owns a writer; sealed segments are immutable and only read.
 #[derive(Debug)]
 pub struct DataFile {
-    pub id: String,
+    pub id: u64,
     path: PathBuf,
     reader: DataFileReader,
-    writer: DataFileWriter,
-    inner: File,
+    writer: Option<DataFileWriter>,
 }
 
 impl DataFile {
-    /// Create a new Datafile, fail is already exist
-    pub fn open(path: PathBuf) -> Result<DataFile> {
+    /// Opens datafile `id` in `dir` for appending, creating it if it does not exist
+    pub fn open(dir: &Path, id: u64) -> Result<DataFile> {
         // Validate if path is a directory
-        if !path.is_dir() {
-            return Err(anyhow!(DataFileError::ErrNotADirectory));
+        if !dir.is_dir() {
+            return Err(anyhow!(DataFileError::NotADirectory));
         }
-        // FIXME: Change file name timestamp-rand_str.dat
-        let file_name = "main.dat".to_string();
-        let path = path.join(&file_name);
-        let inner = File::options()
-            .create(true)
-            .write(true)
-            .open(&path)?;
-        let reader = DataFileReader::new(&path)?;
-        let writer = DataFileWriter::new(&path)?;
+        let path = dir.join(datafile_name(id));
+        let writer = DataFileWriter::new(&path, id)?;
+        let reader = DataFileReader::new(&path, id)?;
+        Ok(DataFile {
+            id,
+            path,
+            reader,
+            writer: Some(writer),
+        })
+    }
+
+    /// Creates datafile `id` in `dir` under a temporary name.
+    /// It only becomes part of the store once [`DataFile::commit`] renames it into place.
+    pub fn create_temp(dir: &Path, id: u64) -> Result<DataFile> {
+        if !dir.is_dir() {
+            return Err(anyhow!(DataFileError::NotADirectory));
+        }
+        let path = temp_path(&dir.join(datafile_name(id)));
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let writer = DataFileWriter::new(&path, id)?;
+        let reader = DataFileReader::new(&path, id)?;
+        Ok(DataFile {
+            id,
+            path,
+            reader,
+            writer: Some(writer),
+        })
+    }
+
+    /// Opens an existing datafile `id` in `dir` as an immutable segment
+    pub fn open_sealed(dir: &Path, id: u64) -> Result<DataFile> {
+        if !dir.is_dir() {
+            return Err(anyhow!(DataFileError::NotADirectory));
+        }
+        let path = dir.join(datafile_name(id));
+        let reader = DataFileReader::new(&path, id)?;
         Ok(DataFile {
-            id: file_name,
+            id,
             path,
             reader,
-            writer,
-            inner,
+            writer: None,
         })
     }
 }
 
 impl Drop for DataFile {
     fn drop(&mut self) {
-        self.inner.sync_all().unwrap();
-        self.writer.sync().unwrap();
+        if let Some(writer) = &self.writer {
+            writer.sync().unwrap();
+        }
     }
 }
 
 impl DataFile {
     // Write key value to datafile and return the offset of value
+    #[cfg(test)]
     pub fn write(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
-        self.writer.append(key, value)
+        self.write_entry(&LogEntry::value(key, value))
+    }
+
+    // Write any kind of record to datafile and return the offset of its value
+    pub fn write_entry(&mut self, entry: &LogEntry) -> Result<u64> {
+        match &mut self.writer {
+            Some(writer) => writer.append(entry),
+            None => Err(anyhow!(DataFileError::Sealed)),
+        }
+    }
+
+    // Write several records with a single write and return the offset of each value
+    pub fn write_all(&mut self, entries: &[LogEntry]) -> Result<Vec<u64>> {
+        match &mut self.writer {
+            Some(writer) => writer.append_all(entries),
+            None => Err(anyhow!(DataFileError::Sealed)),
+        }
     }
 
-    pub fn read(&self, value_offset: u64, value_size: u64) -> Result<Vec<u8>> {
-        self.reader.read(value_offset, value_size)
+    pub fn read(&self, value_offset: u64, key: &[u8], value_size: u64) -> Result<Vec<u8>> {
+        self.reader.read(value_offset, key, value_size)
+    }
+
+    pub fn iter(&self) -> Result<DataFileIterator> {
+        DataFileIterator::new(self.path(), self.id)
+    }
+
+    /// Opens a second, read only handle on the datafile.
+    /// It sees every record the writer appends.
+    pub fn reader(&self) -> Result<DataFile> {
+        Ok(DataFile {
+            id: self.id,
+            path: self.path.clone(),
+            reader: self.reader.try_clone()?,
+            writer: None,
+        })
     }
 
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
 
-    #[allow(dead_code)]
-    // FIXME
-    pub fn read_all(&self) -> Result<Vec<LogEntry>> {
-        self.reader.read_all()
+    /// Opens a handle that fsyncs the datafile without going through the writer
+    pub fn sync_handle(&self) -> Result<File> {
+        match &self.writer {
+            Some(writer) => Ok(writer.inner.try_clone()?),
+            None => Ok(self.reader.inner.try_clone()?),
+        }
+    }
+
+    /// Flushes and closes the writer. The datafile is read only from here on.
+    pub fn seal(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Seals a datafile made by [`DataFile::create_temp`] and atomically renames it
+    /// to its final name. A crash before this leaves only a temporary file behind.
+    pub fn commit(&mut self) -> Result<()> {
+        self.seal()?;
+        let dir = self.path.parent().unwrap_or(Path::new(".")).to_owned();
+        let path = dir.join(datafile_name(self.id));
+        std::fs::rename(&self.path, &path)?;
+        sync_dir(&dir)?;
+        self.path = path;
+        Ok(())
     }
 
-    pub fn compact(&mut self) -> Result<()> {
-        // FIXME: Re implement this function
-        let mut map: HashMap<Vec<u8>, LogEntry> = HashMap::new();
-        let itr = DataFileIterator::new(&self.path)?;
-        for le in itr {
-            if le.value.len() > 0 {
-                map.insert(le.key.to_owned(), le.into());
-            } else {
-                map.remove(&le.key);
+    /// Truncates the datafile to `len` bytes, dropping everything after it
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.truncate(len),
+            None => {
+                let f = File::options().write(true).open(&self.path)?;
+                f.set_len(len)?;
+                f.sync_all()?;
+                Ok(())
             }
         }
-        std::fs::remove_file(&self.path)?;
-        let f = File::options()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(self.path.clone())?;
-        let mut writer = BufWriter::new(&f);
-        for (_, val) in map {
-            let v = bincode::encode_to_vec(
-                val, bincode::config::standard().with_fixed_int_encoding()).unwrap();
-            writer.write(&v).unwrap();
-        }
-        drop(writer);
-
-        self.inner = f;
-        self.writer = DataFileWriter::new(&self.path)?;
-        self.reader = DataFileReader::new(&self.path)?;
+    }
+
+    /// Deletes the datafile from disk
+    pub fn remove(self) -> Result<()> {
+        let path = self.path.clone();
+        drop(self);
+        std::fs::remove_file(path)?;
         Ok(())
     }
 
     pub fn size(&self) -> Result<u64> {
-        Ok(self.inner.metadata().unwrap().len())
+        match &self.writer {
+            Some(writer) => Ok(writer.offset),
+            None => Ok(self.reader.inner.metadata()?.len()),
+        }
     }
 }
 
-fn calculate_key_offset(offset: u64, _le: &LogEntry) -> u64 {
-    return offset + 8;
-}
-
 fn calculate_value_offset(offset: u64, le: &LogEntry) -> u64 {
-    return offset + 16 + le.key_size();
+    offset + log_entry::header_size(le.key_size())
 }
 
 #[derive(Debug)]
 pub struct LogReadResult {
+    /// Offset of the record within the datafile
+    pub offset: u64,
+    pub kind: RecordKind,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
-    pub key_offset: u64,
     pub value_offset: u64,
 }
 
-impl Into<LogEntry> for LogReadResult {
-    fn into(self) -> LogEntry {
+impl From<LogReadResult> for LogEntry {
+    fn from(res: LogReadResult) -> Self {
         LogEntry {
-            key: self.key,
-            value: self.value,
+            kind: res.kind,
+            key: res.key,
+            value: res.value,
         }
     }
 }
 
+/// Iterates over the records of a datafile in write order.
+/// Yields an error and stops at the first record that is incomplete or fails to verify.
 pub struct DataFileIterator {
     inner: BufReader<File>,
+    file_id: u64,
     offset: u64,
+    len: u64,
+    done: bool,
 }
 
 impl DataFileIterator {
-    pub fn new(path: &PathBuf) -> Result<Self> {
+    pub fn new(path: &PathBuf, file_id: u64) -> Result<Self> {
         let f = File::options()
             .read(true)
             .open(path)?;
-        let reader = BufReader::new(f);
+        let len = f.metadata()?.len();
+        let offset = HEADER_SIZE.min(len);
+        let mut reader = BufReader::new(f);
+        reader.seek(SeekFrom::Start(offset))?;
         Ok(DataFileIterator {
             inner: reader,
-            offset: 0,
+            file_id,
+            offset,
+            len,
+            done: false,
         })
     }
+
+    /// Reads the next entry along with the size of its record
+    fn read_entry(&mut self) -> Result<Option<(LogEntry, u64)>> {
+        let remaining = self.len - self.offset;
+        if remaining == 0 {
+            return Ok(None);
+        }
+        // A record running past the end of the file was cut short by a crash
+        let torn = DataFileError::TornWrite {
+            file_id: self.file_id,
+            offset: self.offset,
+        };
+        if remaining < FRAME_SIZE {
+            return Err(anyhow!(torn));
+        }
+        let mut crc = [0u8; CRC_SIZE as usize];
+        let mut len = [0u8; 8];
+        self.inner.read_exact(&mut crc)?;
+        self.inner.read_exact(&mut len)?;
+        let body_len = u64::from_le_bytes(len);
+        if body_len > remaining - FRAME_SIZE {
+            return Err(anyhow!(torn));
+        }
+        let mut body = vec![0u8; body_len as usize];
+        self.inner.read_exact(&mut body)?;
+        match LogEntry::decode_body(&crc, &len, &body) {
+            Some(le) => Ok(Some((le, FRAME_SIZE + body_len))),
+            None => Err(anyhow!(DataFileError::Corrupted {
+                file_id: self.file_id,
+                offset: self.offset,
+            })),
+        }
+    }
 }
 
 impl Iterator for DataFileIterator {
-    type Item = LogReadResult;
+    type Item = Result<LogReadResult>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let res: std::result::Result<LogEntry, bincode::error::DecodeError>
-            = bincode::decode_from_reader(&mut self.inner,
-                                          bincode::config::standard()
-                                              .with_fixed_int_encoding());
-        match res {
-            Ok(le) => {
-                let key_offset = calculate_key_offset(self.offset, &le);
-                let value_offset = calculate_value_offset(self.offset, &le);
+        if self.done {
+            return None;
+        }
+        match self.read_entry() {
+            Ok(Some((le, record_size))) => {
+                let offset = self.offset;
+                let value_offset = calculate_value_offset(offset, &le);
                 // Update offset
-                self.offset += le.size();
-                Some(LogReadResult {
+                self.offset += record_size;
+                Some(Ok(LogReadResult {
+                    offset,
+                    kind: le.kind,
                     key: le.key,
                     value: le.value,
-                    key_offset,
                     value_offset,
-                })
+                }))
             }
-            Err(_e) => {
-                // FIXME
+            Ok(None) => {
+                self.done = true;
                 None
             }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
         }
     }
 }
@@ -185,47 +573,48 @@ impl Iterator for DataFileIterator {
 #[derive(Debug)]
 struct DataFileReader {
     inner: File,
+    file_id: u64,
 }
 
 impl DataFileReader {
-    pub fn new(path: &PathBuf) -> Result<Self> {
+    pub fn new(path: &PathBuf, file_id: u64) -> Result<Self> {
         let f = File::options()
             .read(true)
             .open(path)?;
+        check_version(&f, file_id)?;
         Ok(DataFileReader {
-            inner: f
+            inner: f,
+            file_id,
         })
     }
-    pub fn read(&self, value_offset: u64, value_size: u64) -> Result<Vec<u8>> {
-        let mut buf = vec![0u8; value_size as usize];
-        let bytes_read = self.inner.read_at(&mut buf, value_offset)?;
-        if bytes_read != value_size as usize {
-            return Err(anyhow!(DataFileError::ErrIncompleteRead));
-        }
-        Ok(buf)
-    }
-
-    // FIXME: Fix the offset or add docs around offset
-    #[allow(dead_code)]
-    pub fn read_all(&self) -> Result<Vec<LogEntry>> {
-        let mut r: Vec<LogEntry> = Vec::new();
-        let mut reader = BufReader::new(&self.inner);
-        loop {
-            let res: std::result::Result<LogEntry, bincode::error::DecodeError>
-                = bincode::decode_from_reader(&mut reader,
-                                              bincode::config::standard()
-                                                  .with_fixed_int_encoding());
-            match res {
-                Ok(entry) => {
-                    r.push(entry)
-                }
-                Err(_e) => {
-                    //FIXME
-                    break;
-                }
-            }
+
+    /// Opens a second handle on the same file, which keeps working if the
+    /// datafile is deleted in the meantime
+    fn try_clone(&self) -> Result<Self> {
+        Ok(DataFileReader {
+            inner: self.inner.try_clone()?,
+            file_id: self.file_id,
+        })
+    }
+
+    /// Reads the record holding the value of `key` at `value_offset`, verifies
+    /// its checksum and that it belongs to `key`, and returns the value
+    pub fn read(&self, value_offset: u64, key: &[u8], value_size: u64) -> Result<Vec<u8>> {
+        let header_size = log_entry::header_size(key.len() as u64);
+        let offset = value_offset.checked_sub(header_size)
+            .ok_or_else(|| anyhow!(DataFileError::IncompleteRead))?;
+        let mut buf = vec![0u8; (header_size + value_size) as usize];
+        let bytes_read = self.inner.read_at(&mut buf, offset)?;
+        if bytes_read != buf.len() {
+            return Err(anyhow!(DataFileError::IncompleteRead));
+        }
+        match LogEntry::decode(&buf) {
+            Some(le) if le.key == key && le.value_size() == value_size => Ok(le.value),
+            _ => Err(anyhow!(DataFileError::Corrupted {
+                file_id: self.file_id,
+                offset,
+            })),
         }
-        Ok(r)
     }
 }
 
@@ -237,10 +626,16 @@ struct DataFileWriter {
 }
 
 impl DataFileWriter {
-    pub fn new(path: &PathBuf) -> Result<Self> {
+    pub fn new(path: &PathBuf, file_id: u64) -> Result<Self> {
         let mut f = File::options()
+            .create(true)
+            .read(true)
             .append(true)
             .open(path)?;
+        if read_header(&f, file_id)?.is_none() {
+            f.set_len(0)?;
+            f.write_all(&Header::new(file_id).encode())?;
+        }
         let offset = f.seek(SeekFrom::End(0))?;
         Ok(DataFileWriter {
             inner: f,
@@ -249,24 +644,38 @@ impl DataFileWriter {
         })
     }
 
-    pub fn append(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
-        let entry = LogEntry {
-            key,
-            value,
-        };
-        let value_offset = calculate_value_offset(self.offset, &entry);
-        let bin = bincode::encode_to_vec(&entry,
-                                         bincode::config::standard()
-                                             .with_fixed_int_encoding())?;
-        let bytes_written = self.inner.write(&bin)?;
-        if bytes_written != bin.len() {
-            return Err(anyhow!(DataFileError::ErrIncompleteWrite));
-        }
-        self.byte_written += bytes_written as u64;
-        self.offset += bytes_written as u64;
+    pub fn append(&mut self, entry: &LogEntry) -> Result<u64> {
+        let value_offset = calculate_value_offset(self.offset, entry);
+        let bin = entry.encode()?;
+        self.inner.write_all(&bin)
+            .map_err(|e| anyhow!(e).context(DataFileError::IncompleteWrite))?;
+        self.byte_written += bin.len() as u64;
+        self.offset += bin.len() as u64;
         Ok(value_offset)
     }
 
+    /// Appends the records with one write, so they reach the file together
+    pub fn append_all(&mut self, entries: &[LogEntry]) -> Result<Vec<u64>> {
+        let mut buf = Vec::new();
+        let mut value_offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            value_offsets.push(calculate_value_offset(self.offset + buf.len() as u64, entry));
+            buf.extend_from_slice(&entry.encode()?);
+        }
+        self.inner.write_all(&buf)
+            .map_err(|e| anyhow!(e).context(DataFileError::IncompleteWrite))?;
+        self.byte_written += buf.len() as u64;
+        self.offset += buf.len() as u64;
+        Ok(value_offsets)
+    }
+
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        self.inner.set_len(len)?;
+        self.inner.sync_all()?;
+        self.offset = len;
+        Ok(())
+    }
+
     pub fn sync(&self) -> io::Result<()> {
         self.inner.sync_all()
     }
@@ -283,22 +692,21 @@ mod tests {
     use super::*;
 
     fn rand_string(size: usize) -> String {
-        let s = rand::thread_rng()
+        rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(size)
             .map(char::from)
-            .collect();
-        return s;
+            .collect()
     }
 
     fn rand_key() -> String {
         let rand_ksz = rand::thread_rng().gen_range(10..20) as usize;
-        return rand_string(rand_ksz);
+        rand_string(rand_ksz)
     }
 
     fn rand_value() -> String {
         let rand_ksz = rand::thread_rng().gen_range(10..200) as usize;
-        return rand_string(rand_ksz);
+        rand_string(rand_ksz)
     }
 
     #[test]
@@ -307,8 +715,8 @@ mod tests {
         // Create temp directory
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let temp_dir_path = temp_dir.path().to_owned();
-        let df = DataFile::open(temp_dir_path.clone());
-        assert_eq!(df.is_err(), false);
+        let df = DataFile::open(&temp_dir_path, 1);
+        assert!(df.is_ok());
     }
 
     #[test]
@@ -316,13 +724,13 @@ mod tests {
         // Create temp directory
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let temp_dir_path = temp_dir.path().to_owned();
-        let mut df = DataFile::open(temp_dir_path.clone()).unwrap();
+        let mut df = DataFile::open(&temp_dir_path, 1).unwrap();
         let value_offset = df.write(
             "key".as_bytes().to_vec(),
             "value".as_bytes().to_vec(),
         );
-        assert_eq!(value_offset.is_err(), false);
-        assert_eq!(value_offset.unwrap(), 19);
+        assert!(value_offset.is_ok());
+        assert_eq!(value_offset.unwrap(), HEADER_SIZE + 35);
     }
 
     #[test]
@@ -330,12 +738,12 @@ mod tests {
         // Create temp directory
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let temp_dir_path = temp_dir.path().to_owned();
-        let mut df = DataFile::open(temp_dir_path.clone()).unwrap();
+        let mut df = DataFile::open(&temp_dir_path, 1).unwrap();
         for _ in 1..=1000 {
             let key = rand_key();
             let value = rand_value();
             let res = df.write(key.as_bytes().to_vec(), value.as_bytes().to_vec());
-            assert_eq!(res.is_ok(), true);
+            assert!(res.is_ok());
         }
     }
 
@@ -344,17 +752,17 @@ mod tests {
         // Create temp directory
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let temp_dir_path = temp_dir.path().to_owned();
-        let mut df = DataFile::open(temp_dir_path.clone()).unwrap();
+        let mut df = DataFile::open(&temp_dir_path, 1).unwrap();
         let key = rand_key();
         let value = rand_value();
         let value_sz = value.len() as u64;
         let res = df.write(key.as_bytes().to_vec(), value.as_bytes().to_vec());
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
         // Capture value
         let value_offset = res.unwrap();
         // Test for read
-        let res = df.read(value_offset, value_sz);
-        assert_eq!(res.is_ok(), true);
+        let res = df.read(value_offset, key.as_bytes(), value_sz);
+        assert!(res.is_ok());
         let buf = res.unwrap();
         assert_eq!(value.as_bytes().to_vec(), buf);
     }
@@ -363,18 +771,18 @@ mod tests {
     fn test_bulk_read() {
         let mut key_value_map: HashMap<String, (u64, String)> = HashMap::new();
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut df = DataFile::open(temp_dir.path().to_owned()).unwrap();
+        let mut df = DataFile::open(temp_dir.path(), 1).unwrap();
         for _ in 1..=1000 {
             let key = rand_key();
             let value = rand_value();
             let res = df.write(key.as_bytes().to_vec(), value.as_bytes().to_vec());
-            assert_eq!(res.is_ok(), true);
+            assert!(res.is_ok());
             key_value_map.insert(key, (res.unwrap(), value));
         }
-        for (_key, (offset, value)) in key_value_map.iter() {
+        for (key, (offset, value)) in key_value_map.iter() {
             let value_bytes = value.as_bytes().to_vec();
-            let res = df.read(offset.clone(), value_bytes.len() as u64);
-            assert_eq!(res.is_ok(), true);
+            let res = df.read(*offset, key.as_bytes(), value_bytes.len() as u64);
+            assert!(res.is_ok());
             let buf = res.unwrap();
             assert_eq!(buf, value_bytes);
         }
@@ -383,23 +791,152 @@ mod tests {
     #[test]
     fn test_datafile_iterator() {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut datafile = DataFile::open(temp_dir.path().to_owned()).unwrap();
+        let mut datafile = DataFile::open(temp_dir.path(), 1).unwrap();
         let datafile_path = datafile.path().to_owned();
-        let keys = vec!["k1", "k2", "k3"];
-        let values = vec!["v1", "v2", "v3"];
+        let keys = ["k1", "k2", "k3"];
+        let values = ["v1", "v2", "v3"];
         for i in 0..3 {
             let key = keys[i].as_bytes().to_vec();
             let value = values[i].as_bytes().to_vec();
-            assert_eq!(datafile.write(key, value).is_ok(), true);
+            assert!(datafile.write(key, value).is_ok());
         }
         drop(datafile);
-        let datafile_itr = DataFileIterator::new(&datafile_path).unwrap();
+        let datafile_itr = DataFileIterator::new(&datafile_path, 1).unwrap();
         let mut count = 0;
         for r in datafile_itr {
+            let r = r.unwrap();
             assert_eq!(r.key, keys[count].as_bytes().to_vec());
             assert_eq!(r.value, values[count].as_bytes().to_vec());
             count += 1
         }
         assert_eq!(count, 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_corrupted_read() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut df = DataFile::open(temp_dir.path(), 7).unwrap();
+        df.write(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        let value_offset = df.write(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        // A record read for another key of the same size is not its value
+        let err = df.read(value_offset, b"k3", 2).unwrap_err();
+        assert!(matches!(err.downcast_ref::<DataFileError>(), Some(DataFileError::Corrupted { file_id: 7, .. })));
+        // Flip a bit in the value of the second record
+        let f = File::options().write(true).open(df.path()).unwrap();
+        f.write_at(b"x", value_offset).unwrap();
+
+        let err = df.read(value_offset, b"k2", 2).unwrap_err();
+        match err.downcast_ref::<DataFileError>() {
+            Some(DataFileError::Corrupted { file_id, offset }) => {
+                assert_eq!(*file_id, 7);
+                assert_eq!(*offset, value_offset - log_entry::header_size(2));
+            }
+            _ => panic!("expected corruption error, got {}", err),
+        }
+
+        let mut itr = df.iter().unwrap();
+        assert!(itr.next().unwrap().is_ok());
+        assert!(itr.next().unwrap().is_err());
+        assert!(itr.next().is_none());
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut df = DataFile::open(temp_dir.path(), 1).unwrap();
+        df.write(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        let second = df.size().unwrap();
+        df.write(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        // Cut the second record short, as a crash halfway through append would
+        let len = df.size().unwrap();
+        df.truncate(len - 3).unwrap();
+
+        let mut itr = df.iter().unwrap();
+        assert!(itr.next().unwrap().is_ok());
+        let err = itr.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DataFileError>(),
+            Some(DataFileError::TornWrite { file_id: 1, offset }) if *offset == second
+        ));
+        assert!(itr.next().is_none());
+    }
+
+    #[test]
+    fn test_temp_datafile() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut df = DataFile::create_temp(temp_dir.path(), 2).unwrap();
+        let value_offset = df.write(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        assert!(list_datafiles(temp_dir.path()).unwrap().is_empty());
+
+        df.commit().unwrap();
+        assert_eq!(list_datafiles(temp_dir.path()).unwrap(), vec![2]);
+        assert_eq!(df.read(value_offset, b"k1", 2).unwrap(), b"v1".to_vec());
+
+        // An uncommitted datafile is removed as a leftover
+        DataFile::create_temp(temp_dir.path(), 4).unwrap();
+        remove_temp_files(temp_dir.path()).unwrap();
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_header() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let path = temp_dir.path().join(datafile_name(1));
+        let header = |path: &Path| read_header(&File::open(path).unwrap(), 1).unwrap();
+
+        let mut df = DataFile::open(temp_dir.path(), 1).unwrap();
+        let value_offset = df.write(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        drop(df);
+        let written = header(&path).unwrap();
+        assert_eq!((written.version, written.file_id), (FORMAT_VERSION, 1));
+
+        // A header cut short before any record gets written again
+        std::fs::write(&path, &MAGIC[..3]).unwrap();
+        assert_eq!(header(&path), None);
+        let df = DataFile::open(temp_dir.path(), 1).unwrap();
+        assert_eq!(df.size().unwrap(), HEADER_SIZE);
+        drop(df);
+
+        // A datafile written before headers is left alone
+        let headerless = |key: &[u8], value: &[u8]| {
+            let mut buf = (key.len() as u64).to_le_bytes().to_vec();
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            buf.extend_from_slice(value);
+            buf
+        };
+        let record = [headerless(b"k1", b"v1"), headerless(b"k2", b""), headerless(b"k3", b"v3")].concat();
+        std::fs::write(&path, &record).unwrap();
+        assert_eq!(header(&path).unwrap().version, HEADERLESS_VERSION);
+        let err = DataFile::open(temp_dir.path(), 1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DataFileError>(),
+            Some(DataFileError::UnsupportedVersion { file_id: 1, version: HEADERLESS_VERSION })
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), record);
+
+        // Upgrading rewrites the records as framed records after a new header,
+        // with an empty value turned into a tombstone. A torn last record is dropped.
+        std::fs::write(&path, [&record[..], &headerless(b"k4", b"v4")[..5]].concat()).unwrap();
+        assert!(upgrade(temp_dir.path(), 1).unwrap());
+        assert!(!upgrade(temp_dir.path(), 1).unwrap());
+        let df = DataFile::open_sealed(temp_dir.path(), 1).unwrap();
+        assert_eq!(df.read(value_offset, b"k1", 2).unwrap(), b"v1".to_vec());
+        let records: Vec<_> = df.iter().unwrap().map(|res| {
+            let res = res.unwrap();
+            (res.kind, res.key, res.value)
+        }).collect();
+        assert_eq!(records, vec![
+            (RecordKind::Value, b"k1".to_vec(), b"v1".to_vec()),
+            (RecordKind::Tombstone, b"k2".to_vec(), Vec::new()),
+            (RecordKind::Value, b"k3".to_vec(), b"v3".to_vec()),
+        ]);
+        drop(df);
+
+        // One from a newer version is neither read nor upgraded
+        let newer = Header { version: FORMAT_VERSION + 1, ..Header::new(1) };
+        std::fs::write(&path, newer.encode()).unwrap();
+        assert!(DataFile::open_sealed(temp_dir.path(), 1).is_err());
+        assert!(upgrade(temp_dir.path(), 1).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+
+use crate::Result;
+
+/// Ticket standing for no write at all, always durable. Real tickets start at 1.
+pub const NO_WRITE: u64 = 0;
+
+/// Fsyncs the active datafile on behalf of writers.
+///
+/// Every write takes a ticket, in log order. A writer waiting for its ticket to
+/// be durable either finds it covered by an fsync that already ran, waits for
+/// the one in flight, or runs one covering every write so far. Writers that
+/// arrive while an fsync runs share the next one: group commit.
+pub struct GroupCommit {
+    state: Mutex<State>,
+    synced: Condvar,
+}
+
+struct State {
+    // Handle on the active datafile, replaced on roll over
+    file: Arc<File>,
+    // Tickets handed out so far
+    written: u64,
+    // Every ticket up to this one is durable
+    synced: u64,
+    // Whether a writer is running an fsync
+    syncing: bool,
+    // Number of fsyncs run
+    syncs: u64,
+}
+
+impl GroupCommit {
+    pub fn new(file: File) -> GroupCommit {
+        GroupCommit {
+            state: Mutex::new(State {
+                file: Arc::new(file),
+                written: 0,
+                synced: 0,
+                syncing: false,
+                syncs: 0,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Counts a write appended to the active datafile and returns its ticket.
+    /// Called while holding the writer, so tickets follow log order.
+    pub fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Switches to a new active datafile. The previous one was fsynced when it
+    /// was sealed, so every write so far is durable.
+    pub fn rolled_over(&self, file: File) {
+        let mut state = self.state.lock().unwrap();
+        state.file = Arc::new(file);
+        state.synced = state.written;
+        self.synced.notify_all();
+    }
+
+    /// Waits until write `ticket` is durable, running an fsync if none is in flight
+    pub fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // Cover every write so far, not just ours
+            let target = state.written;
+            let file = state.file.clone();
+            state.syncing = true;
+            drop(state);
+            // Appends only need their data and the file size on disk
+            let res = file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            state.syncs += 1;
+            if res.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+
+    /// Makes every write so far durable
+    pub fn sync(&self) -> Result<()> {
+        let ticket = self.state.lock().unwrap().written;
+        self.wait(ticket)
+    }
+
+    /// Number of fsyncs run so far
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+}
+
+/// Handle to the background thread that fsyncs on an interval.
+/// Dropping it stops the thread.
+pub struct Syncer {
+    sender: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub fn spawn(commit: Arc<GroupCommit>, interval: Duration) -> Result<Syncer> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("kvs-sync".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    if let Err(e) = commit.sync() {
+                        error!("Failed to fsync the active datafile: {}", e);
+                    }
+                }
+            })?;
+        Ok(Syncer {
+            sender,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        let _ = self.sender.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn group_commit(dir: &TempDir) -> GroupCommit {
+        GroupCommit::new(File::create(dir.path().join("active")).unwrap())
+    }
+
+    #[test]
+    fn test_fsync_covers_earlier_writes() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let commit = group_commit(&temp_dir);
+        let tickets: Vec<_> = (0..3).map(|_| commit.written()).collect();
+        commit.wait(tickets[2]).unwrap();
+        commit.wait(tickets[0]).unwrap();
+        commit.wait(tickets[1]).unwrap();
+        assert_eq!(commit.syncs(), 1);
+        commit.sync().unwrap();
+        assert_eq!(commit.syncs(), 1);
+    }
+
+    #[test]
+    fn test_roll_over_is_durable() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let commit = group_commit(&temp_dir);
+        let ticket = commit.written();
+        commit.rolled_over(File::create(temp_dir.path().join("next")).unwrap());
+        commit.wait(ticket).unwrap();
+        assert_eq!(commit.syncs(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_waiters() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let commit = Arc::new(group_commit(&temp_dir));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let commit = commit.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        let ticket = commit.written();
+                        commit.wait(ticket).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(commit.syncs() <= 160);
+        assert_eq!(commit.state.lock().unwrap().synced, 160);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+
+use crate::error::KvError;
+use crate::{CasMismatch, CasResult, KvsEngine, Result, WriteBatch};
+
+/// A [`KvsEngine`] that keeps everything in a `BTreeMap`.
+///
+/// Nothing is written to disk, which makes it handy for tests and as a
+/// baseline in benchmarks. Clones share the same map.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryEngine {
+    inner: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemoryEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.inner.write().unwrap().insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.read().unwrap().get(key.as_ref()).cloned())
+    }
+
+    fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        match self.inner.write().unwrap().remove(key.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(KvError::KeyNotFound)),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.inner.read().unwrap()
+            .range(range)
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect())
+    }
+
+    fn scan_limit<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let inner = self.inner.read().unwrap();
+        let pairs = inner.range(range).map(|(k, v)| (k.to_owned(), v.to_owned()));
+        Ok(if reverse {
+            pairs.rev().take(limit).collect()
+        } else {
+            pairs.take(limit).collect()
+        })
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(self.inner.read().unwrap()
+            .range(range)
+            .map(|(k, _)| k.to_owned())
+            .take(limit)
+            .collect())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        for (key, value) in batch.into_ops() {
+            match value {
+                Some(value) => inner.insert(key, value),
+                None => inner.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult> {
+        let mut inner = self.inner.write().unwrap();
+        let current = inner.get(key.as_ref());
+        if current.map(Vec::as_slice) != expected {
+            return Ok(Err(CasMismatch { current: current.cloned() }));
+        }
+        match new {
+            Some(new) => inner.insert(key.as_ref().to_vec(), new.to_vec()),
+            None => inner.remove(key.as_ref()),
+        };
+        Ok(Ok(()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::error::KvError;
+use crate::{Result, WriteBatch};
+
+pub use memory::MemoryEngine;
+#[cfg(feature = "sled")]
+pub use self::sled::SledEngine;
+
+mod memory;
+#[cfg(feature = "sled")]
+mod sled;
+
+/// A key-value storage engine.
+///
+/// [`KvStore`](crate::KvStore) is the default engine. Code written against this
+/// trait can switch to another backend, such as [`MemoryEngine`](crate::MemoryEngine),
+/// without other changes.
+///
+/// Engines are cheap handles: clones share the same underlying data and can be
+/// moved to other threads, so every operation takes `&self`.
+///
+/// Keys and values are arbitrary bytes. The `String` methods are wrappers over
+/// the byte methods and fail on data that is not valid UTF-8.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a key, overwriting any previous value.
+    fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Gets the value of a key, or `None` if the key does not exist.
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a key. Fails with [`KvError::KeyNotFound`](crate::KvError::KeyNotFound)
+    /// if the key does not exist.
+    fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Returns the key-value pairs with keys inside `range`, in byte order of the keys.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the first `limit` key-value pairs with keys inside `range`, in byte
+    /// order of the keys, or in reverse order from the end of the range if `reverse` is set.
+    ///
+    /// The default goes through [`KvsEngine::scan_bytes`]; engines that can stop
+    /// reading values after `limit` pairs should override it.
+    fn scan_limit<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = self.scan_bytes(range)?;
+        if reverse {
+            pairs.reverse();
+        }
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    /// Returns the first `limit` keys inside `range`, in byte order, without their values.
+    ///
+    /// The default goes through [`KvsEngine::scan_bytes`]; engines that can list
+    /// keys without reading values should override it.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(self.scan_bytes(range)?.into_iter().map(|(key, _)| key).take(limit).collect())
+    }
+
+    /// Applies every operation in `batch` atomically. Readers see all of the
+    /// batch or none of it.
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Atomically replaces the value of a key if it currently is `expected`.
+    ///
+    /// `None` stands for a missing key on either side, so `expected: None` only
+    /// creates the key and `new: None` removes it. On a mismatch nothing is
+    /// written and the current value is returned in a [`CasMismatch`].
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult>;
+
+    /// Sets the value of a key unless it already exists.
+    fn set_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<CasResult> {
+        self.compare_and_swap(key, None, Some(value.as_ref()))
+    }
+
+    /// Removes a key if its value is `expected`.
+    fn remove_if_equals(&self, key: impl AsRef<[u8]>, expected: impl AsRef<[u8]>) -> Result<CasResult> {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+
+    /// Sets the value of a key that expires after `ttl`, reading as missing from then on.
+    /// Engines without expiry fail with [`KvError::Unsupported`](crate::KvError::Unsupported).
+    fn set_bytes_with_ttl(&self, _key: impl AsRef<[u8]>, _value: impl AsRef<[u8]>, _ttl: Duration) -> Result<()> {
+        Err(anyhow!(KvError::Unsupported("TTL")))
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if it never does.
+    /// Fails with [`KvError::KeyNotFound`](crate::KvError::KeyNotFound) if the key does not exist.
+    fn ttl_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        match self.get_bytes(key)? {
+            Some(_) => Ok(None),
+            None => Err(anyhow!(KvError::KeyNotFound)),
+        }
+    }
+
+    /// Makes every write so far durable.
+    fn flush(&self) -> Result<()>;
+
+    /// Reports figures about the engine. Engines that keep nothing on disk only count keys.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.scan_keys(.., usize::MAX)?.len() as u64,
+            ..Stats::default()
+        })
+    }
+
+    /// Sets the value of a key, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if it never does.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key)
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(to_string).transpose()
+    }
+
+    /// Removes a key. Fails with [`KvError::KeyNotFound`](crate::KvError::KeyNotFound)
+    /// if the key does not exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key)
+    }
+
+    /// Returns the key-value pairs with keys inside `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        // UTF-8 strings sort the same as their bytes
+        let range = (to_bytes(range.start_bound()), to_bytes(range.end_bound()));
+        self.scan_bytes(range)?
+            .into_iter()
+            .map(|(key, value)| Ok((to_string(key)?, to_string(value)?)))
+            .collect()
+    }
+}
+
+/// Turns stored bytes back into a `String`
+pub(crate) fn to_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| anyhow!(KvError::InvalidUtf8))
+}
+
+/// Maps a bound on string keys to the same bound on their bytes
+pub(crate) fn to_bytes(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_bytes().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Outcome of a compare-and-swap: `Ok(())` if the swap happened
+pub type CasResult = std::result::Result<(), CasMismatch>;
+
+/// A compare-and-swap found a different value than expected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CasMismatch {
+    /// The value of the key at the time, `None` if it did not exist
+    pub current: Option<Vec<u8>>,
+}
+
+/// Point-in-time figures about an engine.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of live keys
+    pub keys: u64,
+    /// Number of datafiles on disk
+    pub datafiles: u64,
+    /// Bytes taken by datafiles on disk
+    pub disk_bytes: u64,
+    /// Bytes taken by records that were overwritten, removed or have expired, reclaimed by compaction
+    pub dead_bytes: u64,
+    /// Number of fsyncs issued for writes since the engine was opened
+    pub syncs: u64,
+}
@@ -0,0 +1,108 @@
+use std::ops::RangeBounds;
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::error::KvError;
+use crate::{CasMismatch, CasResult, KvsEngine, Result, Stats, WriteBatch};
+
+/// A [`KvsEngine`] backed by the [sled](https://docs.rs/sled) embedded database.
+#[derive(Clone)]
+pub struct SledEngine {
+    db: sled::Db,
+}
+
+impl SledEngine {
+    /// Opens a sled database in the given directory.
+    pub fn open(path: &Path) -> Result<SledEngine> {
+        Ok(SledEngine {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+impl KvsEngine for SledEngine {
+    fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.db.insert(key.as_ref(), value.as_ref())?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        match self.db.remove(key)? {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(KvError::KeyNotFound)),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for res in self.db.range(range) {
+            let (key, value) = res?;
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(pairs)
+    }
+
+    fn scan_limit<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let iter = self.db.range(range);
+        let iter: Box<dyn Iterator<Item = _>> = if reverse { Box::new(iter.rev()) } else { Box::new(iter) };
+        let mut pairs = Vec::new();
+        for res in iter.take(limit) {
+            let (key, value) = res?;
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(pairs)
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for key in self.db.range(range).keys().take(limit) {
+            keys.push(key?.to_vec());
+        }
+        Ok(keys)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in batch.into_ops() {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult> {
+        Ok(self.db.compare_and_swap(key, expected, new)?
+            .map_err(|e| CasMismatch { current: e.current.map(|v| v.to_vec()) }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            ..Stats::default()
+        })
+    }
+}
@@ -1,33 +1,124 @@
+use crate::FORMAT_VERSION;
+
+/// Errors raised while reading or writing datafiles
 #[derive(Debug)]
 pub enum DataFileError {
-    //FIXME: remove Err prefix
-    ErrNotADirectory,
-    ErrIncompleteRead,
-    ErrIncompleteWrite,
+    /// The store path is not a directory
+    NotADirectory,
+    /// Fewer bytes than expected could be read
+    IncompleteRead,
+    /// A record could not be fully written
+    IncompleteWrite,
+    /// The datafile is sealed and no longer accepts writes
+    Sealed,
+    /// A record runs past the end of the datafile, left behind by an interrupted write
+    TornWrite {
+        /// Id of the datafile holding the record
+        file_id: u64,
+        /// Offset of the record within the datafile
+        offset: u64,
+    },
+    /// The store still has the single `main.dat` written before the log was split
+    /// into segments. It can be migrated with [`KvStore::upgrade`](crate::KvStore::upgrade).
+    LegacyDatafile,
+    /// The datafile is in a format version this build does not read. Older
+    /// versions can be migrated with [`KvStore::upgrade`](crate::KvStore::upgrade).
+    UnsupportedVersion {
+        /// Id of the datafile
+        file_id: u64,
+        /// Format version found in its header
+        version: u32,
+    },
+    /// A record failed to decode or did not match its checksum
+    Corrupted {
+        /// Id of the datafile holding the record
+        file_id: u64,
+        /// Offset of the record within the datafile
+        offset: u64,
+    },
 }
 
+/// Errors raised by [`KvStore`](crate::KvStore) operations
 #[derive(Debug)]
 pub enum KvError {
+    /// The key does not exist in the store
     KeyNotFound,
-    EmptyValue,
+    /// A key or value read through the `String` API is not valid UTF-8
+    InvalidUtf8,
+    /// A transaction kept conflicting with other writers and gave up
+    TransactionConflict,
+    /// A merge operand was written or read without a merge operator configured
+    NoMergeOperator,
+    /// A counter holds a value that is not a decimal i64, or would overflow
+    NotAnInteger,
+    /// The engine does not support the named feature
+    Unsupported(&'static str),
+    /// The store was opened read-only and cannot be written to
+    ReadOnly,
+    /// There is no store at the path and it was not allowed to create one
+    StoreNotFound,
+    /// A store already exists at the path and `error_if_exists` was set
+    StoreExists,
+    /// Another handle has the store open for writing
+    StoreLocked {
+        /// Process holding the lock, if it could be read from the LOCK file
+        pid: Option<u32>,
+    },
+    /// The [`Options`](crate::Options) failed validation, for the given reason
+    InvalidOptions(&'static str),
+    /// A `kvs-server` failed to carry out a request
+    Server(String),
 }
 
 impl std::fmt::Display for KvError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             KvError::KeyNotFound => write!(f, "Key not found"),
-            KvError::EmptyValue => write!(f, "Value cannot be empty"),
+            KvError::TransactionConflict => {
+                write!(f, "Transaction conflicted with concurrent writes too many times")
+            }
+            KvError::NoMergeOperator => write!(f, "No merge operator is configured"),
+            KvError::NotAnInteger => write!(f, "Value is not an integer or out of range"),
+            KvError::Unsupported(feature) => write!(f, "{} is not supported by this engine", feature),
+            KvError::ReadOnly => write!(f, "Store is open read-only"),
+            KvError::StoreNotFound => write!(f, "No store found at the path"),
+            KvError::StoreExists => write!(f, "A store already exists at the path"),
+            KvError::StoreLocked { pid: Some(pid) } => {
+                write!(f, "Store is already open for writing by process {}", pid)
+            }
+            KvError::StoreLocked { pid: None } => write!(f, "Store is already open for writing"),
+            KvError::InvalidOptions(reason) => write!(f, "Invalid options: {}", reason),
+            KvError::InvalidUtf8 => write!(f, "Data is not valid UTF-8, use the byte API"),
+            KvError::Server(msg) => write!(f, "Server error: {}", msg),
         }
     }
-    
+
 }
 
 impl std::fmt::Display for DataFileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::ErrNotADirectory => write!(f, "Invalid path. Require a path to directory"),
-            Self::ErrIncompleteRead => write!(f, "Incomplete read"),
-            Self::ErrIncompleteWrite => write!(f, "Incomplete write"),
+            Self::NotADirectory => write!(f, "Invalid path. Require a path to directory"),
+            Self::IncompleteRead => write!(f, "Incomplete read"),
+            Self::IncompleteWrite => write!(f, "Incomplete write"),
+            Self::Sealed => write!(f, "Datafile is sealed and cannot be written to"),
+            Self::TornWrite { file_id, offset } => {
+                write!(f, "Partially written record in datafile {} at offset {}", file_id, offset)
+            }
+            Self::LegacyDatafile => {
+                write!(f, "Store was written by an older version, run `kvs upgrade` to migrate it")
+            }
+            Self::UnsupportedVersion { file_id, version } if *version < FORMAT_VERSION => {
+                write!(f, "Datafile {} is in format version {}, run `kvs upgrade` to migrate it to version {}",
+                       file_id, version, FORMAT_VERSION)
+            }
+            Self::UnsupportedVersion { file_id, version } => {
+                write!(f, "Datafile {} is in format version {}, newer than version {} read by this build",
+                       file_id, version, FORMAT_VERSION)
+            }
+            Self::Corrupted { file_id, offset } => {
+                write!(f, "Corrupted record in datafile {} at offset {}", file_id, offset)
+            }
         }
     }
-}
\ No newline at end of file
+}
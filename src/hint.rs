@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bincode::{Decode, Encode};
+use log::warn;
+
+use crate::datafile::{self, DataFile};
+use crate::log_entry::{self, RecordKind, CRC_SIZE, FRAME_SIZE};
+use crate::Result;
+
+/// Extension of hint files
+const HINT_EXT: &str = "hint";
+
+/*
+* A hint file sits next to a sealed datafile and lists where the value of every
+* key written to that datafile lives, so the index can be rebuilt without reading values.
+* Hint File Format :
+* crc | len | entries
+* u32 | u64 | vec<HintEntry>
+* crc is the crc32 of len and the entries, same as a datafile record.
+*/
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct HintEntry {
+    pub kind: RecordKind,
+    pub key: Vec<u8>,
+    pub file_id: u64,
+    pub value_offset: u64,
+    pub value_sz: u64,
+    pub expires_at: Option<u64>,
+}
+
+fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, HINT_EXT))
+}
+
+/// Builds the hint entries of a datafile by scanning it.
+/// Only the last write of each key within the datafile is kept, along with
+/// the merge operands written after it.
+pub fn build(datafile: &DataFile) -> Result<Vec<HintEntry>> {
+    let mut latest: HashMap<Vec<u8>, Vec<HintEntry>> = HashMap::new();
+    for res in datafile.iter()? {
+        let res = res?;
+        // Sealed datafiles only hold whole batches, their markers carry nothing to index
+        if matches!(res.kind, RecordKind::BatchBegin | RecordKind::BatchCommit) {
+            continue;
+        }
+        let entry = HintEntry {
+            kind: res.kind,
+            key: res.key.clone(),
+            file_id: datafile.id,
+            value_offset: res.value_offset,
+            value_sz: res.value.len() as u64,
+            expires_at: log_entry::expires_at(res.kind, &res.value),
+        };
+        if res.kind.is_operand() {
+            latest.entry(res.key).or_default().push(entry);
+        } else {
+            latest.insert(res.key, vec![entry]);
+        }
+    }
+    Ok(latest.into_values().flatten().collect())
+}
+
+/// Writes the hint file of datafile `id`, replacing any existing one
+pub fn write(dir: &Path, id: u64, entries: &[HintEntry]) -> Result<()> {
+    let body = bincode::encode_to_vec(entries, log_entry::bincode_config())?;
+    let len = (body.len() as u64).to_le_bytes();
+    datafile::write_atomic(&hint_path(dir, id), |f| {
+        f.write_all(&log_entry::checksum(&len, &body).to_le_bytes())?;
+        f.write_all(&len)?;
+        f.write_all(&body)?;
+        Ok(())
+    })
+}
+
+/// Reads the hint file of datafile `id`.
+/// Returns `None` if there is no hint file or it fails verification,
+/// in which case the datafile has to be scanned instead.
+pub fn read(dir: &Path, id: u64) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, id);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let buf = std::fs::read(&path)?;
+    let entries = decode(&buf).filter(|entries| entries.iter().all(|e| e.file_id == id));
+    if entries.is_none() {
+        warn!("Ignoring invalid hint file {}", path.display());
+    }
+    Ok(entries)
+}
+
+fn decode(buf: &[u8]) -> Option<Vec<HintEntry>> {
+    if buf.len() < FRAME_SIZE as usize {
+        return None;
+    }
+    let (crc, rest) = buf.split_at(CRC_SIZE as usize);
+    let (len, body) = rest.split_at(8);
+    if u64::from_le_bytes(len.try_into().ok()?) != body.len() as u64
+        || log_entry::checksum(len, body).to_le_bytes() != crc {
+        return None;
+    }
+    let (entries, read) = bincode::decode_from_slice(body, log_entry::bincode_config()).ok()?;
+    if read != body.len() {
+        return None;
+    }
+    Some(entries)
+}
+
+/// Deletes the hint file of datafile `id`, if there is one
+pub fn remove(dir: &Path, id: u64) -> Result<()> {
+    match std::fs::remove_file(hint_path(dir, id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::LogEntry;
+
+    use super::*;
+
+    #[test]
+    fn test_hint_roundtrip() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut df = DataFile::open(temp_dir.path(), 3).unwrap();
+        df.write(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        let offset = df.write(b"k1".to_vec(), b"v11".to_vec()).unwrap();
+        df.seal().unwrap();
+
+        let entries = build(&df).unwrap();
+        assert_eq!(entries, vec![HintEntry {
+            kind: RecordKind::Value,
+            key: b"k1".to_vec(),
+            file_id: 3,
+            value_offset: offset,
+            value_sz: 3,
+            expires_at: None,
+        }]);
+        write(temp_dir.path(), 3, &entries).unwrap();
+        assert_eq!(read(temp_dir.path(), 3).unwrap(), Some(entries));
+        assert_eq!(read(temp_dir.path(), 4).unwrap(), None);
+    }
+
+    #[test]
+    fn test_hint_keeps_operands() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut df = DataFile::open(temp_dir.path(), 1).unwrap();
+        df.write_entry(&LogEntry::increment(b"k1".to_vec(), 1)).unwrap();
+        let value_offset = df.write(b"k1".to_vec(), b"5".to_vec()).unwrap();
+        let operand_offset = df.write_entry(&LogEntry::increment(b"k1".to_vec(), 2)).unwrap();
+        df.seal().unwrap();
+
+        let kinds: Vec<_> = build(&df).unwrap().iter().map(|e| (e.kind, e.value_offset)).collect();
+        assert_eq!(kinds, vec![(RecordKind::Value, value_offset), (RecordKind::Increment, operand_offset)]);
+    }
+
+    #[test]
+    fn test_corrupted_hint() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let entries = vec![HintEntry {
+            kind: RecordKind::Tombstone,
+            key: b"k1".to_vec(),
+            file_id: 1,
+            value_offset: 35,
+            value_sz: 0,
+            expires_at: None,
+        }];
+        write(temp_dir.path(), 1, &entries).unwrap();
+        let path = hint_path(temp_dir.path(), 1);
+        let mut buf = std::fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+        std::fs::write(&path, buf).unwrap();
+        assert_eq!(read(temp_dir.path(), 1).unwrap(), None);
+    }
+}
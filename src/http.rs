@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::error::KvError;
+use crate::engines::to_string;
+use crate::{KvsEngine, Result, Stats, WriteBatch};
+
+/// Largest request body accepted: 64MB
+const MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+/// Number of keys `GET /keys` lists unless given a `limit`
+const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/*
+* HTTP/JSON API. Keys in paths and query strings are percent-encoded.
+* GET    /keys/{key}              -> 200 {"key": .., "value": ..} | 404
+* PUT    /keys/{key}  {"value"}   -> 204
+* DELETE /keys/{key}              -> 204 | 404
+* POST   /keys/{key}/cas {"expected", "new"}, null meaning absent
+*                                 -> 200 {"swapped": true} | 409 {"swapped": false, "current"}
+* GET    /keys?prefix=&limit=     -> 200 {"keys": [..], "truncated": bool}
+* POST   /batch       {"ops": [{"op": "get"|"set"|"delete", "key", "value"?}]}
+*                                 -> 200 {"results": [{"ok", "value"?, "error"?}]}
+*        Ops run in order and see the writes before them. The writes are applied
+*        atomically once every op has run, a failed op writing nothing.
+* GET    /stats                   -> 200 engine figures and request counters
+* GET    /health                  -> 200 {"status": "ok"}
+* Errors are answered with {"error": message}. Values that are not valid UTF-8
+* get a 422, writes to a read-only store a 405.
+*/
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct CasBody {
+    expected: Option<String>,
+    new: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CasReply {
+    swapped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<Option<String>>,
+}
+
+#[derive(Serialize)]
+struct KeyList {
+    keys: Vec<String>,
+    truncated: bool,
+}
+
+#[derive(Deserialize)]
+struct BatchBody {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get { key: String },
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    results: Vec<BatchResult>,
+}
+
+#[derive(Serialize)]
+struct ServerStats {
+    #[serde(flatten)]
+    engine: Stats,
+    requests: u64,
+    uptime_secs: u64,
+}
+
+/// An error answered with a status code and `{"error": message}`
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> HttpError {
+        HttpError { status, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(e: anyhow::Error) -> HttpError {
+        match e.downcast_ref::<KvError>() {
+            Some(KvError::KeyNotFound) => HttpError::new(404, e.to_string()),
+            Some(KvError::ReadOnly) => HttpError::new(405, e.to_string()),
+            // The value is stored fine, it just cannot be returned as a JSON string
+            Some(KvError::InvalidUtf8) => HttpError::new(422, e.to_string()),
+            _ => HttpError::new(500, e.to_string()),
+        }
+    }
+}
+
+type Reply = std::result::Result<(u16, Option<Vec<u8>>), HttpError>;
+
+struct Context<E: KvsEngine> {
+    engine: E,
+    started: Instant,
+    requests: AtomicU64,
+}
+
+/// Serves the HTTP API on `listener`, one thread per request
+pub fn serve<E: KvsEngine>(engine: E, listener: TcpListener) -> Result<()> {
+    let server = Server::from_listener(listener, None).map_err(|e| anyhow!(e))?;
+    let context = Arc::new(Context {
+        engine,
+        started: Instant::now(),
+        requests: AtomicU64::new(0),
+    });
+    for request in server.incoming_requests() {
+        let context = context.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(&context, request) {
+                error!("Failed to answer HTTP request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle<E: KvsEngine>(context: &Context<E>, mut request: Request) -> Result<()> {
+    context.requests.fetch_add(1, Ordering::Relaxed);
+    debug!("{} {}", request.method(), request.url());
+    let reply = route(context, &mut request);
+    let (status, body) = match reply {
+        Ok((status, body)) => (status, body),
+        Err(e) => (e.status, Some(serde_json::to_vec(&serde_json::json!({ "error": e.message }))?)),
+    };
+    let response = match body {
+        Some(body) => Response::from_data(body)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+        None => Response::from_data(Vec::new()),
+    };
+    request.respond(response.with_status_code(status))?;
+    Ok(())
+}
+
+fn route<E: KvsEngine>(context: &Context<E>, request: &mut Request) -> Reply {
+    let engine = &context.engine;
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let method = request.method().clone();
+    if let Some(key) = path.strip_prefix("/keys/").and_then(|p| p.strip_suffix("/cas")) {
+        let key = percent_decode(key, false)?;
+        if method != Method::Post {
+            return Err(method_not_allowed());
+        }
+        let body: CasBody = read_json(request)?;
+        return match engine.compare_and_swap(key, body.expected.as_deref().map(str::as_bytes),
+                                             body.new.as_deref().map(str::as_bytes))? {
+            Ok(()) => json(200, CasReply { swapped: true, current: None }),
+            Err(mismatch) => {
+                let current = mismatch.current.map(to_string).transpose()?;
+                json(409, CasReply { swapped: false, current: Some(current) })
+            }
+        };
+    }
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = percent_decode(key, false)?;
+        return match method {
+            Method::Get => match engine.get(key.clone())? {
+                Some(value) => json(200, KeyValue { key, value }),
+                None => Err(HttpError::new(404, KvError::KeyNotFound.to_string())),
+            },
+            Method::Put => {
+                let body: PutBody = read_json(request)?;
+                engine.set(key, body.value)?;
+                Ok((204, None))
+            }
+            Method::Delete => {
+                engine.remove(key)?;
+                Ok((204, None))
+            }
+            _ => Err(method_not_allowed()),
+        };
+    }
+    match (method, path) {
+        (Method::Get, "/keys") => {
+            let mut prefix = String::new();
+            let mut limit = DEFAULT_LIST_LIMIT;
+            for (name, value) in parse_query(query)? {
+                match name.as_str() {
+                    "prefix" => prefix = value,
+                    "limit" => {
+                        limit = value.parse()
+                            .map_err(|_| HttpError::new(400, "limit must be a non-negative integer"))?;
+                    }
+                    _ => return Err(HttpError::new(400, format!("unknown parameter '{}'", name))),
+                }
+            }
+            // Keys starting with `prefix` come first among the keys from it on,
+            // so listing `limit + 1` of them is enough, and no value gets read
+            let mut keys = engine.scan_keys(prefix.as_bytes().to_vec().., limit.saturating_add(1))?
+                .into_iter()
+                .take_while(|key| key.starts_with(prefix.as_bytes()))
+                .map(to_string)
+                .collect::<Result<Vec<_>>>()?;
+            let truncated = keys.len() > limit;
+            keys.truncate(limit);
+            json(200, KeyList { keys, truncated })
+        }
+        (Method::Post, "/batch") => {
+            let body: BatchBody = read_json(request)?;
+            let mut batch = WriteBatch::new();
+            // Values set or deleted by the ops so far, `None` when deleted
+            let mut written = HashMap::new();
+            let mut results = Vec::with_capacity(body.ops.len());
+            for op in body.ops {
+                let res = match op {
+                    BatchOp::Get { key } => batch_get(engine, &written, key),
+                    BatchOp::Set { key, value } => {
+                        batch.put(&key, &value);
+                        written.insert(key, Some(value));
+                        Ok(None)
+                    }
+                    BatchOp::Delete { key } => match batch_get(engine, &written, key.clone()) {
+                        Ok(Some(_)) => {
+                            batch.delete(&key);
+                            written.insert(key, None);
+                            Ok(None)
+                        }
+                        Ok(None) => Err(anyhow!(KvError::KeyNotFound)),
+                        Err(e) => Err(e),
+                    },
+                };
+                results.push(match res {
+                    Ok(value) => BatchResult { ok: true, value, error: None },
+                    Err(e) => BatchResult { ok: false, value: None, error: Some(e.to_string()) },
+                });
+            }
+            engine.write(batch)?;
+            json(200, BatchResponse { results })
+        }
+        (Method::Get, "/stats") => json(200, ServerStats {
+            engine: engine.stats()?,
+            requests: context.requests.load(Ordering::Relaxed),
+            uptime_secs: context.started.elapsed().as_secs(),
+        }),
+        (Method::Get, "/health") => json(200, serde_json::json!({ "status": "ok" })),
+        (_, "/keys" | "/batch" | "/stats" | "/health") => Err(method_not_allowed()),
+        _ => Err(HttpError::new(404, format!("no route for {}", path))),
+    }
+}
+
+/// Reads `key` as seen by a batch op: the value set by an earlier op of the
+/// batch if there is one, the stored value otherwise
+fn batch_get<E: KvsEngine>(engine: &E, written: &HashMap<String, Option<String>>, key: String)
+                           -> Result<Option<String>> {
+    match written.get(&key) {
+        Some(value) => Ok(value.clone()),
+        None => engine.get(key),
+    }
+}
+
+fn json(status: u16, body: impl Serialize) -> Reply {
+    let body = serde_json::to_vec(&body).map_err(|e| HttpError::new(500, e.to_string()))?;
+    Ok((status, Some(body)))
+}
+
+fn method_not_allowed() -> HttpError {
+    HttpError::new(405, "method not allowed")
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(request: &mut Request) -> std::result::Result<T, HttpError> {
+    let mut body = Vec::new();
+    request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut body)
+        .map_err(|e| HttpError::new(400, e.to_string()))?;
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err(HttpError::new(413, "request body too large"));
+    }
+    serde_json::from_slice(&body).map_err(|e| HttpError::new(400, format!("invalid JSON body: {}", e)))
+}
+
+fn parse_query(query: &str) -> std::result::Result<Vec<(String, String)>, HttpError> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes. `+` stands for a space in query strings only,
+/// in paths it is a literal `+`.
+fn percent_decode(s: &str, plus_as_space: bool) -> std::result::Result<String, HttpError> {
+    let invalid = || HttpError::new(400, format!("invalid percent-encoding in '{}'", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().ok_or_else(invalid)?, iter.next().ok_or_else(invalid)?];
+                // `from_str_radix` would also take a sign
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(invalid());
+                }
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb+c", true).ok(), Some("a/b c".to_owned()));
+        assert_eq!(percent_decode("a%2Fb+c", false).ok(), Some("a/b+c".to_owned()));
+        assert_eq!(percent_decode("%2B+", true).ok(), Some("+ ".to_owned()));
+        assert_eq!(percent_decode("%e2%9c%93", false).ok(), Some("\u{2713}".to_owned()));
+        assert!(percent_decode("%2", false).is_err());
+        assert!(percent_decode("%zz", false).is_err());
+        assert!(percent_decode("%ff", false).is_err());
+        assert!(percent_decode("%+f", false).is_err());
+    }
+}
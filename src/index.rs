@@ -1,43 +1,331 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
 
-#[derive(Debug, Clone)]
+use crate::log_entry::{self, RecordKind};
+use crate::options::IndexType;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Entry {
-    pub file_id: String,
+    /// `Value`, or the kind of the merge operand that created the key
+    pub kind: RecordKind,
+    pub file_id: u64,
+    pub value_offset: u64,
+    pub value_sz: u64,
+    /// Bumped on every write of the key, so transactions can tell it changed.
+    /// Only kept in memory and left alone when compaction moves the value.
+    pub seq: u64,
+    /// Merge operands written since, oldest first. They are folded into the value on read.
+    pub operands: Vec<Location>,
+    /// When the key expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// Where a record of a key lives
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub kind: RecordKind,
+    pub file_id: u64,
     pub value_offset: u64,
     pub value_sz: u64,
 }
 
+impl Location {
+    /// Size of the whole record
+    pub fn record_size(&self, key: &[u8]) -> u64 {
+        log_entry::header_size(key.len() as u64) + self.value_sz
+    }
+}
+
+impl Entry {
+    fn new(location: Location, seq: u64, expires_at: Option<u64>) -> Entry {
+        Entry {
+            kind: location.kind,
+            file_id: location.file_id,
+            value_offset: location.value_offset,
+            value_sz: location.value_sz,
+            seq,
+            operands: Vec::new(),
+            expires_at,
+        }
+    }
+
+    /// Whether the key has expired at `now`, in milliseconds since the Unix epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Every record the value of the key is folded from, oldest first
+    pub fn records(&self) -> impl Iterator<Item = Location> + '_ {
+        let first = Location {
+            kind: self.kind,
+            file_id: self.file_id,
+            value_offset: self.value_offset,
+            value_sz: self.value_sz,
+        };
+        std::iter::once(first).chain(self.operands.iter().cloned())
+    }
+}
+
+/// The map behind a [`KeyDir`], picked by [`IndexType`]
+#[derive(Debug)]
+enum Map {
+    BTree(BTreeMap<Vec<u8>, Entry>),
+    Hash(HashMap<Vec<u8>, Entry>),
+}
+
+impl Map {
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        match self {
+            Map::BTree(m) => m.get(key),
+            Map::Hash(m) => m.get(key),
+        }
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        match self {
+            Map::BTree(m) => m.get_mut(key),
+            Map::Hash(m) => m.get_mut(key),
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, e: Entry) -> Option<Entry> {
+        match self {
+            Map::BTree(m) => m.insert(key, e),
+            Map::Hash(m) => m.insert(key, e),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        match self {
+            Map::BTree(m) => m.remove(key),
+            Map::Hash(m) => m.remove(key),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &Entry)> + '_> {
+        match self {
+            Map::BTree(m) => Box::new(m.iter()),
+            Map::Hash(m) => Box::new(m.iter()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct KeyDir {
-    inner: HashMap<String, Entry>
+    inner: Map,
+    // Bytes per datafile taken by records the index no longer points at
+    dead_bytes: HashMap<u64, u64>,
+    // Sequence number handed to the next write
+    next_seq: u64,
 }
 
 impl KeyDir {
-
-    pub fn new() -> Self {
+    pub fn new(index: IndexType) -> Self {
+        let inner = match index {
+            IndexType::BTree => Map::BTree(BTreeMap::new()),
+            IndexType::Hash => Map::Hash(HashMap::new()),
+        };
         Self {
-            inner: HashMap::new()
+            inner,
+            dead_bytes: HashMap::new(),
+            next_seq: 0,
         }
     }
-    pub fn put(&mut self, file_id: String, key: String, value_offset: u64, value_sz: u64) {
-        let e = Entry {
-            file_id,
-            value_sz,
-            value_offset
-        };
+
+    pub fn put(&mut self, key: Vec<u8>, location: Location, expires_at: Option<u64>) {
+        self.next_seq += 1;
+        let e = Entry::new(location, self.next_seq, expires_at);
         let hm = &mut self.inner;
-        hm.insert(key, e);
+        if let Some(old) = hm.insert(key.clone(), e) {
+            self.add_dead_entry(&key, &old);
+        }
+    }
+
+    /// Chains a merge operand onto `key`, creating the key if it does not exist
+    pub fn merge(&mut self, kind: RecordKind, file_id: u64, key: Vec<u8>, value_offset: u64, value_sz: u64) {
+        self.next_seq += 1;
+        let location = Location { kind, file_id, value_offset, value_sz };
+        match self.inner.get_mut(&key) {
+            Some(e) => {
+                e.operands.push(location);
+                e.seq = self.next_seq;
+            }
+            None => {
+                self.inner.insert(key, Entry::new(location, self.next_seq, None));
+            }
+        }
     }
 
-    pub fn get(&self, key: &str) -> Option<Entry> {
-        self.inner.get(key).map(|e|  e.clone())
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.inner.get(key).cloned()
     }
 
-    pub fn contains_key(&self, key: &str) -> bool {
-        self.inner.contains_key(key)
+    /// Same as [`KeyDir::get`], treating a key expired at `now` as missing
+    pub fn get_live(&self, key: &[u8], now: u64) -> Option<Entry> {
+        self.inner.get(key).filter(|e| !e.is_expired(now)).cloned()
     }
 
-    pub fn remove_key(&mut self, key: &str) -> Option<Entry> {
-        self.inner.remove(key)
+    /// Replaces the first `copied` records of `key` with the records compaction
+    /// wrote for them, keeping its sequence number. Returns false and leaves the key
+    /// alone if those records are no longer the ones `old` started with.
+    pub fn relocate(&mut self, key: &[u8], old: &Entry, copied: usize, moved: &[Location]) -> bool {
+        let e = match self.inner.get_mut(key) {
+            Some(e) => e,
+            None => return false,
+        };
+        if !e.records().take(copied).eq(old.records().take(copied)) {
+            return false;
+        }
+        let mut records = moved.iter().cloned();
+        let first = records.next().expect("relocated to no records");
+        e.kind = first.kind;
+        e.file_id = first.file_id;
+        e.value_offset = first.value_offset;
+        e.value_sz = first.value_sz;
+        e.operands.splice(..copied - 1, records);
+        true
+    }
+
+    pub fn remove_key(&mut self, key: &[u8]) -> Option<Entry> {
+        let old = self.inner.remove(key);
+        if let Some(e) = &old {
+            self.add_dead_entry(key, e);
+        }
+        old
+    }
+
+    /// Counts every record of an entry that was overwritten or removed as dead
+    fn add_dead_entry(&mut self, key: &[u8], e: &Entry) {
+        for location in e.records() {
+            self.add_dead_bytes(location.file_id, location.record_size(key));
+        }
+    }
+
+    /// Number of keys that have not expired at `now`
+    pub fn live_len(&self, now: u64) -> usize {
+        self.inner.iter().filter(|(_, e)| !e.is_expired(now)).count()
+    }
+
+    /// Iterates over every key, in key order for a B-tree index
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        self.inner.iter()
+    }
+
+    /// Iterates over the keys inside `range` in key order.
+    /// A hash index has to collect and sort them first.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Box<dyn DoubleEndedIterator<Item = (&Vec<u8>, &Entry)> + '_> {
+        match &self.inner {
+            Map::BTree(m) => Box::new(m.range(range)),
+            Map::Hash(m) => {
+                let mut keys: Vec<_> = m.iter().filter(|(key, _)| range.contains(*key)).collect();
+                keys.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                Box::new(keys.into_iter())
+            }
+        }
+    }
+
+    pub fn add_dead_bytes(&mut self, file_id: u64, size: u64) {
+        *self.dead_bytes.entry(file_id).or_default() += size;
+    }
+
+    pub fn dead_bytes(&self, file_id: u64) -> u64 {
+        self.dead_bytes.get(&file_id).copied().unwrap_or_default()
+    }
+
+    /// Bytes per datafile taken by the records of keys expired at `now`.
+    /// They are not dead yet, as the index still points at them until compaction drops them.
+    pub fn expired_bytes(&self, now: u64) -> HashMap<u64, u64> {
+        let mut expired: HashMap<u64, u64> = HashMap::new();
+        for (key, e) in self.inner.iter().filter(|(_, e)| e.is_expired(now)) {
+            for location in e.records() {
+                *expired.entry(location.file_id).or_default() += location.record_size(key);
+            }
+        }
+        expired
     }
-}
\ No newline at end of file
+
+    /// Drops the accounting of a datafile that has been deleted
+    pub fn forget_file(&mut self, file_id: u64) {
+        self.dead_bytes.remove(&file_id);
+    }
+
+    /// Recomputes dead bytes from datafile sizes and the records the index points at
+    pub fn rebuild_dead_bytes(&mut self, sizes: impl Iterator<Item = (u64, u64)>) {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for (key, e) in self.inner.iter() {
+            for location in e.records() {
+                *live.entry(location.file_id).or_default() += location.record_size(key);
+            }
+        }
+        self.dead_bytes = sizes
+            .map(|(id, size)| (id, size.saturating_sub(live.get(&id).copied().unwrap_or_default())))
+            .collect();
+    }
+}
+
+/// The range covering every key that starts with `prefix`
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // The first key past the prefix: bump the last byte that is not already 0xff
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(file_id: u64, value_offset: u64, value_sz: u64) -> Location {
+        Location { kind: RecordKind::Value, file_id, value_offset, value_sz }
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        assert_eq!(prefix_range(b"ab"), (Bound::Included(b"ab".to_vec()), Bound::Excluded(b"ac".to_vec())));
+        assert_eq!(prefix_range(b"a\xff"), (Bound::Included(b"a\xff".to_vec()), Bound::Excluded(b"b".to_vec())));
+        assert_eq!(prefix_range(b"\xff"), (Bound::Included(b"\xff".to_vec()), Bound::Unbounded));
+        assert_eq!(prefix_range(b""), (Bound::Included(Vec::new()), Bound::Unbounded));
+    }
+
+    #[test]
+    fn test_merge_chain() {
+        let mut key_dir = KeyDir::new(IndexType::BTree);
+        key_dir.merge(RecordKind::Increment, 1, b"k".to_vec(), 10, 8);
+        key_dir.put(b"k".to_vec(), value(1, 100, 1), None);
+        key_dir.merge(RecordKind::Increment, 1, b"k".to_vec(), 200, 8);
+        key_dir.merge(RecordKind::Increment, 3, b"k".to_vec(), 300, 8);
+        let old = key_dir.get(b"k").unwrap();
+        assert_eq!(old.records().map(|l| l.value_offset).collect::<Vec<_>>(), vec![100, 200, 300]);
+        // The first value and its operand are dead once overwritten
+        assert_eq!(key_dir.dead_bytes(1), log_entry::header_size(1) + 8);
+
+        // Compaction folds the records it copied, those written since stay chained
+        key_dir.merge(RecordKind::Increment, 5, b"k".to_vec(), 400, 8);
+        let folded = value(2, 0, 2);
+        assert!(key_dir.relocate(b"k", &old, 2, std::slice::from_ref(&folded)));
+        let e = key_dir.get(b"k").unwrap();
+        assert_eq!(e.records().map(|l| (l.kind, l.file_id)).collect::<Vec<_>>(), vec![
+            (RecordKind::Value, 2), (RecordKind::Increment, 3), (RecordKind::Increment, 5),
+        ]);
+        assert!(!key_dir.relocate(b"k", &old, 2, &[folded]));
+    }
+
+    #[test]
+    fn test_ordered_range() {
+        for index in [IndexType::BTree, IndexType::Hash] {
+            let mut key_dir = KeyDir::new(index);
+            for key in [b"b1", b"a2", b"a1", b"c1"] {
+                key_dir.put(key.to_vec(), value(1, 0, 1), None);
+            }
+            let keys: Vec<_> = key_dir.range(prefix_range(b"a")).map(|(key, _)| key.clone()).collect();
+            assert_eq!(keys, vec![b"a1".to_vec(), b"a2".to_vec()]);
+            let keys: Vec<_> = key_dir.range(b"b".to_vec()..).rev().map(|(key, _)| key.clone()).collect();
+            assert_eq!(keys, vec![b"c1".to_vec(), b"b1".to_vec()]);
+        }
+    }
+}
@@ -1,73 +1,487 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 
 use anyhow::{anyhow, Ok};
+use log::warn;
 
-use crate::datafile::{DataFile, DataFileIterator};
-use crate::index::KeyDir;
-use crate::log_entry::LogEntry;
-use crate::Result;
-use crate::error::KvError;
+use crate::batch::WriteBatch;
+use crate::transaction::{Transaction, MAX_TRANSACTION_RETRIES};
+use crate::datafile::{self, DataFile, LogReadResult};
+use crate::compaction::{self, Compactor, Job};
+use crate::engines;
+use crate::hint;
+use crate::index::{self, KeyDir, Location};
+use crate::lock::DirLock;
+use crate::log_entry::{self, LogEntry, RecordKind};
+use crate::merge::{self, MAX_MERGE_OPERANDS};
+use crate::durability::{self, GroupCommit, Syncer};
+use crate::options::{KvStoreBuilder, Options, SyncMode};
+use crate::{CasMismatch, CasResult, KvsEngine, Result, Stats};
+use crate::error::{DataFileError, KvError};
 
 /// Key-value store implementation.
+///
+/// A `KvStore` is a handle that can be cloned and shared between threads.
+/// Reads never wait on writers: they go straight to the datafiles with positional
+/// reads. Writes are appended by one handle at a time.
+#[derive(Clone)]
 pub struct KvStore {
-    path: PathBuf,
-    active_datafile: DataFile,
-    // FIXME: Scan for data file in path
-    old_datafiles: Vec<DataFile>,
-    key_dir: KeyDir
+    // The single writer, holding the only writable handle on the active datafile
+    active_datafile: Arc<Mutex<DataFile>>,
+    shared: Arc<Shared>,
+    // None in read-only mode
+    compactor: Option<Arc<Compactor>>,
+    commit: Arc<GroupCommit>,
+    // Fsyncs in the background for `SyncMode::Interval`, stopped when the last handle goes
+    _syncer: Option<Arc<Syncer>>,
+}
+
+/// State shared between the store and its compaction worker
+pub(crate) struct Shared {
+    pub path: PathBuf,
+    pub options: Options,
+    pub key_dir: RwLock<KeyDir>,
+    // Read only handle on the active datafile, swapped on roll over
+    pub active: RwLock<Arc<DataFile>>,
+    // Sealed datafiles keyed by id, oldest first
+    pub sealed: RwLock<BTreeMap<u64, Arc<DataFile>>>,
+    // Held while a compaction runs so the worker and `compact` never overlap
+    pub compaction_lock: Mutex<()>,
+    // Keeps other writers out of the directory until the compactor is done too.
+    // None in read-only mode.
+    _dir_lock: Option<DirLock>,
+}
+
+impl Shared {
+    pub fn merge_operator(&self) -> Option<&dyn merge::MergeOperator> {
+        self.options.merge_operator.as_deref()
+    }
 }
 
 impl KvStore {
 
     /// Opens a KvStore at the given path.
     pub fn open(path: &Path) -> Result<KvStore> {
-        let df = DataFile::open(path.to_owned()).unwrap();
-        let mut key_dir = KeyDir::new();
-        Self::init_index(&df, &mut key_dir);
-        Ok(Self {
-            active_datafile: df,
+        Self::open_with(path, Options::default())
+    }
+
+    /// Opens an existing KvStore at the given path without ever writing to it.
+    ///
+    /// Nothing in the directory is created, written, truncated or compacted, so
+    /// this works on a read-only filesystem and alongside a live writer. Reads
+    /// see the store as it was when it was opened, and every write fails with
+    /// [`KvError::ReadOnly`]. See [`Options::read_only`].
+    pub fn open_read_only(path: &Path) -> Result<KvStore> {
+        Self::open_with(path, Options { read_only: true, ..Options::default() })
+    }
+
+    /// Opens a KvStore at the given path using the given options.
+    ///
+    /// Every datafile in the directory is replayed in id order, and the newest one
+    /// becomes the active datafile new writes are appended to.
+    ///
+    /// The directory stays locked while the store is open, so opening it for
+    /// writing a second time fails with [`KvError::StoreLocked`]. Read-only
+    /// opens do not take the lock.
+    pub fn open_with(path: &Path, options: Options) -> Result<KvStore> {
+        options.validate()?;
+        let read_only = options.read_only;
+        if !path.exists() && options.create_if_missing && !read_only {
+            std::fs::create_dir_all(path)?;
+        }
+        if !path.exists() {
+            return Err(anyhow!(KvError::StoreNotFound));
+        }
+        if !path.is_dir() {
+            return Err(anyhow!(DataFileError::NotADirectory));
+        }
+        // Never mistake an older store's records for a segment's
+        datafile::check_legacy_datafile(path)?;
+        // Checked before taking the lock, so that a failed open leaves no LOCK file behind
+        let ids = datafile::list_datafiles(path)?;
+        if ids.is_empty() && (read_only || !options.create_if_missing) {
+            return Err(anyhow!(KvError::StoreNotFound));
+        }
+        if !ids.is_empty() && options.error_if_exists {
+            return Err(anyhow!(KvError::StoreExists));
+        }
+        let dir_lock = if read_only { None } else { Some(DirLock::acquire(path)?) };
+        if !read_only {
+            datafile::remove_temp_files(path)?;
+        }
+        let (key_dir, sealed, active_datafile) = loop {
+            // Listed again, as another writer may have held the lock until now
+            let ids = datafile::list_datafiles(path)?;
+            match Self::replay(path, ids, &options) {
+                // A live writer compacted datafiles away while they were read, list them again
+                Err(e) if read_only && is_not_found(&e) => continue,
+                res => break res?,
+            }
+        };
+        let commit = Arc::new(GroupCommit::new(active_datafile.sync_handle()?));
+        let syncer = match options.sync {
+            SyncMode::Interval(millis) if !read_only => {
+                Some(Arc::new(Syncer::spawn(commit.clone(), Duration::from_millis(millis))?))
+            }
+            _ => None,
+        };
+        let shared = Arc::new(Shared {
             path: path.to_owned(),
-            old_datafiles: Vec::new(),
-            key_dir
+            options,
+            key_dir: RwLock::new(key_dir),
+            active: RwLock::new(Arc::new(active_datafile.reader()?)),
+            sealed: RwLock::new(sealed),
+            compaction_lock: Mutex::new(()),
+            _dir_lock: dir_lock,
+        });
+        let compactor = if read_only {
+            None
+        } else {
+            Some(Arc::new(Compactor::spawn(shared.clone())?))
+        };
+        Ok(Self {
+            active_datafile: Arc::new(Mutex::new(active_datafile)),
+            shared,
+            compactor,
+            commit,
+            _syncer: syncer,
         })
     }
 
+    /// Opens the datafiles with the given ids and rebuilds the index from them.
+    /// Returns the index, the sealed datafiles and the active one.
+    fn replay(path: &Path, mut ids: Vec<u64>, options: &Options)
+              -> Result<(KeyDir, BTreeMap<u64, Arc<DataFile>>, DataFile)> {
+        let active_id = ids.pop().unwrap_or(1);
+        let read_only = options.read_only;
+        let mut key_dir = KeyDir::new(options.index);
+        let mut sealed = BTreeMap::new();
+        for id in ids {
+            let df = DataFile::open_sealed(path, id)?;
+            match hint::read(path, id)? {
+                Some(entries) => {
+                    for e in entries {
+                        Self::apply_kind(&mut key_dir, e.kind, e.file_id, e.key,
+                                         e.value_offset, e.value_sz, e.expires_at);
+                    }
+                }
+                None => {
+                    // Only the active datafile can end in an interrupted write,
+                    // sealed ones were fsynced whole
+                    if let Some(offset) = Self::init_index(&df, &mut key_dir)? {
+                        return Err(anyhow!(DataFileError::Corrupted { file_id: id, offset }));
+                    }
+                    if !read_only {
+                        hint::write(path, id, &hint::build(&df)?)?;
+                    }
+                }
+            }
+            sealed.insert(id, Arc::new(df));
+        }
+        let mut active_datafile = if read_only {
+            DataFile::open_sealed(path, active_id)?
+        } else {
+            DataFile::open(path, active_id)?
+        };
+        if let Some(len) = Self::init_index(&active_datafile, &mut key_dir)? {
+            if !read_only {
+                active_datafile.truncate(len)?;
+            }
+        }
+        // Headers hold no records, so they count as neither live nor dead
+        let mut sizes = vec![(active_id, active_datafile.size()?.saturating_sub(datafile::HEADER_SIZE))];
+        for (id, df) in sealed.iter() {
+            sizes.push((*id, df.size()?.saturating_sub(datafile::HEADER_SIZE)));
+        }
+        key_dir.rebuild_dead_bytes(sizes.into_iter());
+        Ok((key_dir, sealed, active_datafile))
+    }
+
+    /// Migrates the store at the given path from an older on-disk format to
+    /// [`FORMAT_VERSION`](crate::FORMAT_VERSION) in place. Returns the number of
+    /// datafiles rewritten.
+    ///
+    /// The store must not be open. Each datafile is rewritten on its own, so an
+    /// interrupted upgrade can simply be run again.
+    pub fn upgrade(path: &Path) -> Result<usize> {
+        if !path.is_dir() {
+            return Err(anyhow!(DataFileError::NotADirectory));
+        }
+        let _lock = DirLock::acquire(path)?;
+        datafile::remove_temp_files(path)?;
+        datafile::migrate_legacy_datafile(path)?;
+        let mut upgraded = 0;
+        for id in datafile::list_datafiles(path)? {
+            if datafile::upgrade(path, id)? {
+                upgraded += 1;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    /// Starts setting up the [`Options`] to open a KvStore at the given path with.
+    pub fn builder(path: impl AsRef<Path>) -> KvStoreBuilder {
+        KvStoreBuilder::new(path.as_ref())
+    }
+
+    /// Returns the options the store was opened with.
+    pub fn config(&self) -> &Options {
+        &self.shared.options
+    }
+
+    /// Locks the writer, failing with [`KvError::ReadOnly`] if the store cannot be written to
+    fn writer(&self) -> Result<MutexGuard<'_, DataFile>> {
+        if self.shared.options.read_only {
+            return Err(anyhow!(KvError::ReadOnly));
+        }
+        Ok(self.active_datafile.lock().unwrap())
+    }
+
     /// Sets a key-value pair in the store.
     ///
     /// # Arguments
     ///
     /// * `key` - The key.
     /// * `value` - The value.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        if value.is_empty() {
-            return Err(anyhow!(KvError::EmptyValue));
-        }
-        // FIXME: Move compaction to background thread
-        if self.active_datafile.size()? > 10 * 1024 /* 10KB */ {
-            self.active_datafile.compact()?;
-            Self::init_index(&self.active_datafile, &mut self.key_dir);
-        }
-        // FIXME Keep track of file size. Then Check and run compaction here
-        self._key(key, value)
-    }
-
-    fn _key(&mut self, key: String, value: String) -> Result<()> {
-        let key_bytes = key.as_bytes().to_vec();
-        let value_bytes = value.as_bytes().to_vec();
-        let value_sz = value_bytes.len() as u64;
-        // Write the key value entry to datafile
-        let value_offset = self.active_datafile.write(key_bytes, value_bytes)?;
-        let file_id = self.active_datafile.id.to_owned();
-        // FIXME: Below line add side effects to this method
-        // We should move that away
-        // Update key dir
-        if value_sz > 0 {
-            let _ = &self.key_dir.put(file_id, key, value_offset, value_sz);
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Sets a key-value pair in the store. Keys and values can be any bytes.
+    pub fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.append(LogEntry::value(key.as_ref().to_vec(), value.as_ref().to_vec()))
+    }
+
+    /// Sets a key-value pair that expires after `ttl`.
+    ///
+    /// Once expired the key reads as missing, and compaction drops it from disk.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    /// Sets a key-value pair that expires after `ttl`. Keys and values can be any bytes.
+    pub fn set_bytes_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        let expires_at = log_entry::now_millis().saturating_add(ttl.as_millis() as u64);
+        self.append(LogEntry::expiring(key.as_ref().to_vec(), value.as_ref().to_vec(), expires_at))
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if it never does.
+    /// Fails with [`KvError::KeyNotFound`] if the key does not exist.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key)
+    }
+
+    /// Same as [`KvStore::ttl`], for keys given as bytes.
+    pub fn ttl_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let now = log_entry::now_millis();
+        match self.shared.key_dir.read().unwrap().get_live(key.as_ref(), now) {
+            Some(e) => Ok(e.expires_at.map(|expires_at| Duration::from_millis(expires_at - now))),
+            None => Err(anyhow!(KvError::KeyNotFound)),
+        }
+    }
+
+    /// Appends a record to the active datafile, points the index at it and
+    /// seals the datafile once it is full
+    fn append(&self, entry: LogEntry) -> Result<()> {
+        let ticket = self.append_locked(&mut *self.writer()?, entry)?;
+        self.durable(ticket)
+    }
+
+    /// Same as [`KvStore::append`], for callers already holding the writer.
+    /// Returns the ticket to hand to [`KvStore::durable`] once the writer is released.
+    fn append_locked(&self, active: &mut DataFile, entry: LogEntry) -> Result<u64> {
+        // Write the entry to datafile
+        let value_offset = active.write_entry(&entry)?;
+        let file_id = active.id;
+        // Update key dir while still holding the writer, so the index follows log order
+        Self::apply(&mut self.shared.key_dir.write().unwrap(), file_id, entry, value_offset);
+        let ticket = self.commit.written();
+        self.maybe_roll_over(active)?;
+        Ok(ticket)
+    }
+
+    /// Waits for the write with `ticket` to be fsynced, if the sync mode asks for it.
+    /// Called after releasing the writer, so that other writers can share the fsync.
+    fn durable(&self, ticket: u64) -> Result<()> {
+        match self.shared.options.sync {
+            SyncMode::Always => self.commit.wait(ticket),
+            SyncMode::EveryN(n) if ticket.is_multiple_of(n) => self.commit.wait(ticket),
+            _ => Ok(()),
+        }
+    }
+
+    /// Seals the active datafile once it is full and hands it to the compactor
+    fn maybe_roll_over(&self, active: &mut DataFile) -> Result<()> {
+        if active.size()? >= self.shared.options.max_segment_size {
+            let id = self.roll_over(active)?;
+            if let Some(compactor) = &self.compactor {
+                compactor.submit(Job::Sealed { id, output_id: id + 1 });
+            }
         }
         Ok(())
     }
 
+    /// Adds `delta` to the counter stored at `key`, a decimal integer.
+    /// A missing key counts as 0.
+    ///
+    /// The delta is appended as a merge operand and only added up when the key
+    /// is read or compacted, so updating a counter never rewrites its value. A key
+    /// holding something other than an integer fails to read with
+    /// [`KvError::NotAnInteger`] until it is set again.
+    pub fn incr_by(&self, key: impl AsRef<[u8]>, delta: i64) -> Result<()> {
+        self.append_operand(LogEntry::increment(key.as_ref().to_vec(), delta))
+    }
+
+    /// Appends a merge operand for `key`, folded into its value by
+    /// [`Options::merge_operator`] when the key is read or compacted.
+    ///
+    /// Fails with [`KvError::NoMergeOperator`] if the store has no merge operator.
+    pub fn merge(&self, key: impl AsRef<[u8]>, operand: impl AsRef<[u8]>) -> Result<()> {
+        if self.shared.options.merge_operator.is_none() {
+            return Err(anyhow!(KvError::NoMergeOperator));
+        }
+        self.append_operand(LogEntry::merge(key.as_ref().to_vec(), operand.as_ref().to_vec()))
+    }
+
+    /// Appends a merge operand, or folds it into a full value once the key
+    /// has [`MAX_MERGE_OPERANDS`] of them
+    fn append_operand(&self, entry: LogEntry) -> Result<()> {
+        let mut active = self.writer()?;
+        let now = log_entry::now_millis();
+        let current = self.shared.key_dir.read().unwrap().get(&entry.key);
+        // Operands always follow the value of the key they were written to,
+        // so one for an expired key starts it over as a full value
+        let fold = current.as_ref()
+            .is_some_and(|e| e.is_expired(now) || e.operands.len() + 1 >= MAX_MERGE_OPERANDS);
+        let ticket = if fold {
+            let existing = self.get_bytes(&entry.key)?;
+            let value = merge::apply(self.shared.merge_operator(), entry.kind, &entry.key, existing, entry.value)?;
+            // The folded value keeps the expiry of the key it replaces
+            let folded = match current.filter(|e| !e.is_expired(now)).and_then(|e| e.expires_at) {
+                Some(expires_at) => LogEntry::expiring(entry.key, value, expires_at),
+                None => LogEntry::value(entry.key, value),
+            };
+            self.append_locked(&mut active, folded)?
+        } else {
+            self.append_locked(&mut active, entry)?
+        };
+        drop(active);
+        self.durable(ticket)
+    }
+
+    /// Applies every operation in `batch` atomically.
+    ///
+    /// The batch is written between begin and commit markers with a single write.
+    /// After a crash, a batch without its commit marker is dropped as a whole.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let ticket = self.write_locked(&mut *self.writer()?, batch)?;
+        self.durable(ticket)
+    }
+
+    /// Same as [`KvStore::write`], for callers already holding the writer.
+    /// Returns the ticket of the batch, like [`KvStore::append_locked`].
+    fn write_locked(&self, active: &mut DataFile, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(durability::NO_WRITE);
+        }
+        let len = batch.len() as u64;
+        let mut entries = Vec::with_capacity(batch.len() + 2);
+        entries.push(LogEntry::batch_marker(RecordKind::BatchBegin, len));
+        entries.extend(batch.into_entries());
+        entries.push(LogEntry::batch_marker(RecordKind::BatchCommit, len));
+
+        let value_offsets = active.write_all(&entries)?;
+        let file_id = active.id;
+        {
+            // Readers see all of the batch or none of it
+            let mut key_dir = self.shared.key_dir.write().unwrap();
+            for (entry, value_offset) in entries.into_iter().zip(value_offsets) {
+                Self::apply(&mut key_dir, file_id, entry, value_offset);
+            }
+        }
+        let ticket = self.commit.written();
+        self.maybe_roll_over(active)?;
+        Ok(ticket)
+    }
+
+    /// Atomically replaces the value of a key if it currently is `expected`.
+    /// See [`KvsEngine::compare_and_swap`].
+    pub fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult> {
+        let key = key.as_ref();
+        // Holding the writer keeps the value from changing between the check and the write
+        let mut active = self.writer()?;
+        let current = self.get_bytes(key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(CasMismatch { current }));
+        }
+        let ticket = match (new, current) {
+            (Some(new), _) => self.append_locked(&mut active, LogEntry::value(key.to_vec(), new.to_vec()))?,
+            (None, Some(_)) => self.append_locked(&mut active, LogEntry::tombstone(key.to_vec()))?,
+            // Already absent
+            (None, None) => durability::NO_WRITE,
+        };
+        drop(active);
+        self.durable(ticket)?;
+        Ok(CasResult::Ok(()))
+    }
+
+    /// Sets the value of a key unless it already exists.
+    pub fn set_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<CasResult> {
+        self.compare_and_swap(key, None, Some(value.as_ref()))
+    }
+
+    /// Removes a key if its value is `expected`.
+    pub fn remove_if_equals(&self, key: impl AsRef<[u8]>, expected: impl AsRef<[u8]>) -> Result<CasResult> {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+
+    /// Runs `f` as an optimistic transaction and returns what it returns.
+    ///
+    /// Reads go through the store and writes are buffered in the [`Transaction`].
+    /// On commit, the writes are applied atomically unless another writer changed
+    /// a key the transaction read, in which case `f` runs again. After
+    /// [`MAX_TRANSACTION_RETRIES`] conflicts this fails with
+    /// [`KvError::TransactionConflict`]. An error from `f` aborts the transaction
+    /// without writing anything.
+    pub fn transaction<T>(&self, mut f: impl FnMut(&mut Transaction) -> Result<T>) -> Result<T> {
+        for _ in 0..=MAX_TRANSACTION_RETRIES {
+            let mut txn = Transaction::new(self);
+            let res = f(&mut txn)?;
+            if self.commit(txn)? {
+                return Ok(res);
+            }
+        }
+        Err(anyhow!(KvError::TransactionConflict))
+    }
+
+    /// Applies the writes of `txn` if nothing it read has changed since.
+    /// Returns whether it was applied.
+    fn commit(&self, txn: Transaction) -> Result<bool> {
+        let (reads, batch) = txn.into_parts();
+        // Holding the writer keeps every other write out between checking and writing
+        let mut active = self.writer()?;
+        {
+            let key_dir = self.shared.key_dir.read().unwrap();
+            let now = log_entry::now_millis();
+            if reads.iter().any(|(key, seq)| key_dir.get_live(key, now).map(|e| e.seq) != *seq) {
+                return Ok(false);
+            }
+        }
+        let ticket = self.write_locked(&mut active, batch)?;
+        drop(active);
+        self.durable(ticket)?;
+        Ok(true)
+    }
+
     /// Retrieves the value associated with the given key from the store.
     ///
     /// # Arguments
@@ -78,13 +492,113 @@ impl KvStore {
     ///
     /// The value associated with the key, if it exists.
     pub fn get(&self, key: String) -> Result<Option<String>> {
-        if !self.key_dir.contains_key(&key) {
-            return Ok(None)
+        self.get_bytes(key)?.map(engines::to_string).transpose()
+    }
+
+    /// Retrieves the value associated with the given key from the store as bytes.
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key.as_ref())?.map(|(_, value)| value))
+    }
+
+    /// Reads the value of `key` along with the sequence number of the write that set it
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        'lookup: loop {
+            // get key metadata from key dir
+            let e = match self.shared.key_dir.read().unwrap().get_live(key, log_entry::now_millis()) {
+                Some(e) => e,
+                None => return Ok(None),
+            };
+            let active = self.shared.active.read().unwrap().clone();
+            // Fold the value and the merge operands chained after it, oldest first
+            let mut value = None;
+            for location in e.records() {
+                let datafile = if location.file_id == active.id {
+                    Some(active.clone())
+                } else {
+                    self.shared.sealed.read().unwrap().get(&location.file_id).cloned()
+                };
+                let df = match datafile {
+                    Some(df) => df,
+                    // Compaction moved the value between the two lookups, look it up again
+                    None => continue 'lookup,
+                };
+                let record = df.read(location.value_offset, key, location.value_sz)?;
+                value = Some(merge::apply(self.shared.merge_operator(), location.kind, key, value, record)?);
+            }
+            return Ok(value.map(|value| (e.seq, value)));
+        }
+    }
+
+    /// Iterates over the key-value pairs with keys inside `range`, in key order.
+    ///
+    /// The keys are picked when the scan starts and values are read as the
+    /// iterator advances, skipping keys removed in the meantime. Use `rev` to
+    /// walk the range backwards and `take` to limit it:
+    ///
+    /// ```no_run
+    /// # fn main() -> kvs::Result<()> {
+    /// # let store = kvs::KvStore::open(std::path::Path::new("."))?;
+    /// let newest: Vec<_> = store.scan(b"a".to_vec()..).rev().take(10).collect::<kvs::Result<_>>()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<'_> {
+        let keys: Vec<Vec<u8>> = self.shared.key_dir.read().unwrap()
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect();
+        Scan {
+            store: self,
+            keys: keys.into_iter(),
+        }
+    }
+
+    /// Returns the first `limit` keys inside `range`, in key order, straight from
+    /// the index without reading any value.
+    pub fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Vec<Vec<u8>> {
+        let now = log_entry::now_millis();
+        self.shared.key_dir.read().unwrap()
+            .range(range)
+            .filter(|(_, e)| !e.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect()
+    }
+
+    /// Iterates over the key-value pairs whose keys start with `prefix`, in key order.
+    /// Works like [`KvStore::scan`].
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        self.scan(index::prefix_range(prefix.as_ref()))
+    }
+
+    /// Fsyncs the active datafile, making every write so far durable whatever
+    /// the [`SyncMode`].
+    pub fn flush(&self) -> Result<()> {
+        if self.shared.options.read_only {
+            return Ok(());
         }
-        // get key metadata from key dir
-        let e = self.key_dir.get(&key).unwrap();
-        let read_op = self.active_datafile.read(e.value_offset, e.value_sz)?;
-        Ok(Some(std::str::from_utf8(&read_op).unwrap().to_string()))
+        self.commit.sync()
+    }
+
+    /// Reports the number of keys and how much of the disk they take up.
+    pub fn stats(&self) -> Result<Stats> {
+        let mut files = vec![self.shared.active.read().unwrap().clone()];
+        files.extend(self.shared.sealed.read().unwrap().values().cloned());
+        let key_dir = self.shared.key_dir.read().unwrap();
+        // Expired keys read as missing, and compaction reclaims their records
+        let now = log_entry::now_millis();
+        let expired = key_dir.expired_bytes(now);
+        let mut stats = Stats {
+            keys: key_dir.live_len(now) as u64,
+            datafiles: files.len() as u64,
+            syncs: self.commit.syncs(),
+            ..Stats::default()
+        };
+        for df in files {
+            stats.disk_bytes += df.size()?;
+            stats.dead_bytes += key_dir.dead_bytes(df.id) + expired.get(&df.id).copied().unwrap_or_default();
+        }
+        Ok(stats)
     }
 
     /// Removes the key-value pair associated with the given key from the store.
@@ -92,36 +606,244 @@ impl KvStore {
     /// # Arguments
     ///
     /// * `key` - The key.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.key_dir.contains_key(&key) {
-            let _ = self.key_dir.remove_key(&key).is_some();
-            return self._key(key.clone(), "".to_string())
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key)
+    }
+
+    /// Removes the key-value pair associated with the given key from the store.
+    pub fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        // Checked under the writer so a concurrent remove cannot slip in between
+        let mut active = self.writer()?;
+        if self.shared.key_dir.read().unwrap().get_live(key, log_entry::now_millis()).is_none() {
+            return Err(anyhow!(KvError::KeyNotFound));
         }
-        return Err(anyhow!(KvError::KeyNotFound))
+        let ticket = self.append_locked(&mut active, LogEntry::tombstone(key.to_vec()))?;
+        drop(active);
+        self.durable(ticket)
+    }
+
+    /// Seals the active datafile and starts a new one.
+    ///
+    /// The id right after the sealed datafile is left free, it is where
+    /// a compaction writes its output. Returns the id of the sealed datafile.
+    fn roll_over(&self, active: &mut DataFile) -> Result<u64> {
+        // The sealed datafile must be on disk whole before a newer one exists
+        active.seal()?;
+        let next = DataFile::open(&self.shared.path, active.id + 2)?;
+        let reader = Arc::new(next.reader()?);
+        let sync_handle = next.sync_handle()?;
+        let sealed = std::mem::replace(active, next);
+        self.commit.rolled_over(sync_handle);
+        let id = sealed.id;
+        // Readers holding an index entry for the sealed datafile must find it
+        // in `sealed` before the active handle moves on
+        self.shared.sealed.write().unwrap().insert(id, Arc::new(sealed));
+        *self.shared.active.write().unwrap() = reader;
+        Ok(id)
     }
 
+    /// Merges all sealed datafiles, and the active one if it holds any data,
+    /// into a single datafile with only live values.
+    ///
+    /// Compaction normally runs in the background once the thresholds in
+    /// [`Options`] are crossed; this runs it right away and returns when it is done.
+    pub fn compact(&self) -> Result<()> {
+        let output_id = {
+            let mut active = self.writer()?;
+            // The output goes right below the active datafile. Once an earlier
+            // compaction has taken that id, only rolling over frees another one.
+            let taken = self.shared.sealed.read().unwrap().contains_key(&(active.id - 1));
+            if active.size()? > datafile::HEADER_SIZE || (taken && compaction::has_dead_bytes(&self.shared)) {
+                self.roll_over(&mut active)?;
+            }
+            active.id - 1
+        };
+        // Writes carry on while the merge runs
+        compaction::compact(&self.shared, output_id)
+    }
 
-    /// Initializes the index
-    fn init_index(datafile: &DataFile, key_dir: &mut KeyDir) {
-        let reader = DataFileIterator::new(datafile.path()).unwrap();
-        let file_id = datafile.id.to_owned();
-        for res in reader {
-            let key = std::str::from_utf8(&res.key).unwrap().to_string();
-            let value_offset = res.value_offset;
-            let le: LogEntry = res.into();
-            let value_sz = le.value_size();
-            if value_sz > 0 {
+    /// Initializes the index from a datafile.
+    ///
+    /// A partially written record at the end of the datafile is left over from a
+    /// crash mid-append, and so is a batch missing its commit marker, none of
+    /// which is applied. Returns the length to truncate the datafile to so that
+    /// new writes start on a clean record, if it has such a tail.
+    fn init_index(datafile: &DataFile, key_dir: &mut KeyDir) -> Result<Option<u64>> {
+        let file_id = datafile.id;
+        // Offset of the open batch and the records read since its begin marker
+        let mut batch: Option<(u64, Vec<LogReadResult>)> = None;
+        for res in datafile.iter()? {
+            if let Some(&DataFileError::TornWrite { offset, .. }) = res.as_ref().err()
+                .and_then(|e| e.downcast_ref::<DataFileError>()) {
+                warn!("Partially written record in datafile {} at offset {}", file_id, offset);
+                return Ok(Some(batch.map_or(offset, |(start, _)| start)));
+            }
+            let res = res?;
+            match (res.kind, &mut batch) {
+                (RecordKind::BatchBegin, None) => batch = Some((res.offset, vec![res])),
+                (RecordKind::BatchCommit, Some((_, records))) => {
+                    records.push(res);
+                    for res in batch.take().unwrap().1 {
+                        let value_offset = res.value_offset;
+                        Self::apply(key_dir, file_id, res.into(), value_offset);
+                    }
+                }
+                (RecordKind::BatchBegin | RecordKind::BatchCommit, _) => {
+                    return Err(anyhow!(DataFileError::Corrupted { file_id, offset: res.offset }));
+                }
+                (_, Some((_, records))) => records.push(res),
+                (_, None) => {
+                    let value_offset = res.value_offset;
+                    Self::apply(key_dir, file_id, res.into(), value_offset);
+                }
+            }
+        }
+        if let Some((offset, _)) = batch {
+            warn!("Uncommitted batch in datafile {} at offset {}", file_id, offset);
+            return Ok(Some(offset));
+        }
+        Ok(None)
+    }
+
+    /// Applies a single write to the index
+    fn apply(key_dir: &mut KeyDir, file_id: u64, entry: LogEntry, value_offset: u64) {
+        let value_sz = entry.value_size();
+        let expires_at = entry.expires_at();
+        Self::apply_kind(key_dir, entry.kind, file_id, entry.key, value_offset, value_sz, expires_at);
+    }
+
+    fn apply_kind(key_dir: &mut KeyDir, kind: RecordKind, file_id: u64, key: Vec<u8>,
+                  value_offset: u64, value_sz: u64, expires_at: Option<u64>) {
+        match kind {
+            RecordKind::Value | RecordKind::ExpiringValue => {
                 key_dir.put(
-                    file_id.to_owned(),
-                    key.clone(),
-                    value_offset,
-                    value_sz
+                    key,
+                    Location { kind, file_id, value_offset, value_sz },
+                    expires_at,
                 );
-            } else { // value_sz == 0 represent a deleted key
-                if key_dir.contains_key(&key) {
-                    key_dir.remove_key(&key);
-                }
+            }
+            RecordKind::Merge | RecordKind::Increment => {
+                key_dir.merge(kind, file_id, key, value_offset, value_sz);
+            }
+            RecordKind::Tombstone => {
+                key_dir.remove_key(&key);
+                // A tombstone is garbage as soon as it is written
+                key_dir.add_dead_bytes(file_id, log_entry::header_size(key.len() as u64));
+            }
+            RecordKind::BatchBegin | RecordKind::BatchCommit => {
+                key_dir.add_dead_bytes(file_id, log_entry::header_size(0) + value_sz);
+            }
+        }
+    }
+}
+
+/// Whether `e` comes from a file that does not exist
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        KvStore::set_bytes(self, key, value)
+    }
+
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        KvStore::get_bytes(self, key)
+    }
+
+    fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        KvStore::remove_bytes(self, key)
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        KvStore::scan(self, range).collect()
+    }
+
+    fn scan_limit<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Values are read as the scan advances, so only `limit` of them are
+        let scan = KvStore::scan(self, range);
+        if reverse {
+            scan.rev().take(limit).collect()
+        } else {
+            scan.take(limit).collect()
+        }
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(KvStore::scan_keys(self, range, limit))
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        KvStore::write(self, batch)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult> {
+        KvStore::compare_and_swap(self, key, expected, new)
+    }
+
+    fn set_bytes_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        KvStore::set_bytes_with_ttl(self, key, value, ttl)
+    }
+
+    fn ttl_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        KvStore::ttl_bytes(self, key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+}
+
+/// Iterator over key-value pairs in key order, returned by [`KvStore::scan`]
+/// and [`KvStore::scan_prefix`].
+pub struct Scan<'a> {
+    store: &'a KvStore,
+    keys: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl Scan<'_> {
+    /// Reads the value of `key`, `None` if it was removed since the scan started
+    fn load(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let value = self.store.get_bytes(&key).transpose()?;
+        Some(value.map(|value| (key, value)))
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next() {
+            if let Some(res) = self.load(key) {
+                return Some(res);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for Scan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next_back() {
+            if let Some(res) = self.load(key) {
+                return Some(res);
             }
         }
+        None
     }
 }
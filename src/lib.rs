@@ -1,16 +1,42 @@
 #![deny(missing_docs)]
 //! A key-value store library
 
-pub use cli::{Cli, Command};
-pub use kv::KvStore;
+pub use batch::WriteBatch;
+pub use cli::{Cli, ClientCli, Command, Engine, LocalCommand, Protocol, ServerCli};
+pub use client::KvsClient;
+pub use engines::{CasMismatch, CasResult, KvsEngine, MemoryEngine, Stats};
+#[cfg(feature = "sled")]
+pub use engines::SledEngine;
+pub use datafile::FORMAT_VERSION;
+pub use error::{DataFileError, KvError};
+pub use kv::{KvStore, Scan};
+pub use merge::{MergeOperator, MAX_MERGE_OPERANDS};
+pub use options::{IndexType, KvStoreBuilder, Options, SyncMode};
+pub use transaction::{Transaction, MAX_TRANSACTION_RETRIES};
+pub use protocol::{Request, Response, ScanQuery, DEFAULT_ADDR};
+pub use server::KvsServer;
 use log_entry::LogEntry;
 
+mod batch;
 mod cli;
+mod client;
+mod compaction;
+mod engines;
 mod kv;
+mod merge;
 mod log_entry;
 mod datafile;
+mod durability;
+mod hint;
+mod http;
 mod index;
+mod lock;
 mod error;
+mod options;
+mod protocol;
+mod resp;
+mod server;
+mod transaction;
 
 /// KvStore custom error
 pub type Result<T> = anyhow::Result<T>;
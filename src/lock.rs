@@ -0,0 +1,64 @@
+use std::fs::{File, TryLockError};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::error::KvError;
+use crate::Result;
+
+/// Name of the file a writable store locks in its directory
+const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive advisory lock on a store directory, released when dropped.
+///
+/// The lock is an `flock` on the LOCK file, so the OS drops it along with a
+/// process that crashes. The file is left in place and holds the PID of the
+/// last holder.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing with [`KvError::StoreLocked`] if another handle holds it,
+    /// in this process or any other
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(anyhow!(KvError::StoreLocked { pid: pid.trim().parse().ok() }));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(DirLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let lock = DirLock::acquire(temp_dir.path()).unwrap();
+        let err = DirLock::acquire(temp_dir.path()).unwrap_err();
+        let pid = std::process::id();
+        assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::StoreLocked { pid: Some(p) }) if *p == pid));
+        drop(lock);
+        DirLock::acquire(temp_dir.path()).unwrap();
+    }
+}
@@ -1,28 +1,190 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use bincode::{Decode, Encode};
 
+use crate::Result;
+
+/// Size of the crc32 checksum that prefixes every record
+pub const CRC_SIZE: u64 = 4;
+/// Size of the record frame: checksum followed by body length
+pub const FRAME_SIZE: u64 = CRC_SIZE + 8;
+/// Size of the expiry time at the start of an `ExpiringValue` record's value
+pub const EXPIRY_SIZE: usize = 8;
+
 /*
 * LogEntry is the basic unit of the log.
-* Log Entry Format :
-* ksz | key | vsz | value
-* u64 | vec<u8> | u64 | vec<u8>
+* Record Format :
+* crc | len | kind | ksz | key | vsz | value
+* u32 | u64 | u32 | u64 | vec<u8> | u64 | vec<u8>
+* len is the size of the bincode encoded body that follows (kind..value),
+* crc is the crc32 of len and the body.
 */
 #[derive(Debug, Encode, Decode, Clone)]
 pub struct LogEntry {
+    pub kind: RecordKind,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
 
+/// What a record does to its key
+#[derive(Debug, Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// Sets the key to the record's value
+    Value,
+    /// Deletes the key. The value is always empty.
+    Tombstone,
+    /// Starts a batch. The key is empty and the value holds the number of
+    /// records in the batch as a little endian u64.
+    BatchBegin,
+    /// Ends a batch, same layout as `BatchBegin`. The records in between only
+    /// take effect once this marker is on disk.
+    BatchCommit,
+    /// A merge operand, folded into the key's value by the store's merge operator
+    Merge,
+    /// Adds a little endian i64 to the key's value, a decimal integer
+    Increment,
+    /// Sets the key to the record's value until it expires. The value starts with
+    /// the expiry time in milliseconds since the Unix epoch, as a little endian u64.
+    ExpiringValue,
+}
+
+impl RecordKind {
+    /// Whether records of this kind are chained onto the key's value rather than replacing it
+    pub fn is_operand(self) -> bool {
+        matches!(self, RecordKind::Merge | RecordKind::Increment)
+    }
+}
+
+pub fn bincode_config() -> impl bincode::config::Config {
+    bincode::config::standard().with_fixed_int_encoding()
+}
+
+/// Number of bytes in a record before the value, for a key of `key_size` bytes
+pub fn header_size(key_size: u64) -> u64 {
+    FRAME_SIZE + 4 + 16 + key_size
+}
+
+/// Expiry time of a record, if it has one
+pub fn expires_at(kind: RecordKind, value: &[u8]) -> Option<u64> {
+    match kind {
+        RecordKind::ExpiringValue => Some(u64::from_le_bytes(value.get(..EXPIRY_SIZE)?.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, as used for expiry times
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Checksum of a record's length field and body
+pub fn checksum(len: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(body);
+    hasher.finalize()
+}
+
 impl LogEntry {
-    pub fn size(&self) -> u64 {
-        return 16 + self.key_size() + self.value_size();
+    pub fn value(key: Vec<u8>, value: Vec<u8>) -> Self {
+        LogEntry {
+            kind: RecordKind::Value,
+            key,
+            value,
+        }
+    }
+
+    pub fn tombstone(key: Vec<u8>) -> Self {
+        LogEntry {
+            kind: RecordKind::Tombstone,
+            key,
+            value: Vec::new(),
+        }
+    }
+
+    /// Builds a value that expires at `expires_at`, in milliseconds since the Unix epoch
+    pub fn expiring(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Self {
+        let mut buf = Vec::with_capacity(EXPIRY_SIZE + value.len());
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+        buf.extend_from_slice(&value);
+        LogEntry {
+            kind: RecordKind::ExpiringValue,
+            key,
+            value: buf,
+        }
+    }
+
+    pub fn merge(key: Vec<u8>, operand: Vec<u8>) -> Self {
+        LogEntry {
+            kind: RecordKind::Merge,
+            key,
+            value: operand,
+        }
+    }
+
+    pub fn increment(key: Vec<u8>, delta: i64) -> Self {
+        LogEntry {
+            kind: RecordKind::Increment,
+            key,
+            value: delta.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Builds a batch marker for a batch of `len` records
+    pub fn batch_marker(kind: RecordKind, len: u64) -> Self {
+        LogEntry {
+            kind,
+            key: Vec::new(),
+            value: len.to_le_bytes().to_vec(),
+        }
     }
 
     pub fn key_size(&self) -> u64 {
-        return self.key.len() as u64
+        self.key.len() as u64
     }
 
     pub fn value_size(&self) -> u64 {
-        return self.value.len() as u64
+        self.value.len() as u64
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        expires_at(self.kind, &self.value)
+    }
+
+    /// Encodes the entry into a framed, checksummed record
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = bincode::encode_to_vec(self, bincode_config())?;
+        let len = (body.len() as u64).to_le_bytes();
+        let mut buf = Vec::with_capacity(FRAME_SIZE as usize + body.len());
+        buf.extend_from_slice(&checksum(&len, &body).to_le_bytes());
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&body);
+        Ok(buf)
     }
-}
 
+    /// Decodes a record produced by [`LogEntry::encode`].
+    /// Returns `None` if the record is malformed or fails its checksum.
+    pub fn decode(buf: &[u8]) -> Option<LogEntry> {
+        if buf.len() < FRAME_SIZE as usize {
+            return None;
+        }
+        let (crc, rest) = buf.split_at(CRC_SIZE as usize);
+        let (len, body) = rest.split_at(8);
+        if u64::from_le_bytes(len.try_into().ok()?) != body.len() as u64 {
+            return None;
+        }
+        Self::decode_body(crc, len, body)
+    }
+
+    /// Verifies `body` against the frame's `crc` and `len` bytes and decodes it
+    pub fn decode_body(crc: &[u8], len: &[u8], body: &[u8]) -> Option<LogEntry> {
+        if checksum(len, body).to_le_bytes() != crc {
+            return None;
+        }
+        let (le, read) = bincode::decode_from_slice(body, bincode_config()).ok()?;
+        if read != body.len() {
+            return None;
+        }
+        Some(le)
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use anyhow::anyhow;
+
+use crate::error::KvError;
+use crate::log_entry::{self, RecordKind};
+use crate::Result;
+
+/// Number of merge operands a key can chain before the next one is folded into
+/// a full value on write, bounding the records a read has to fold
+pub const MAX_MERGE_OPERANDS: usize = 32;
+
+/// Folds merge operands into values, set with
+/// [`Options::merge_operator`](crate::Options::merge_operator).
+///
+/// Operands written by [`KvStore::merge`](crate::KvStore::merge) are kept in the
+/// log as they are and folded one at a time, oldest first, when the key is read
+/// or compacted. Any `Fn(&[u8], Option<&[u8]>, &[u8]) -> Result<Vec<u8>>` closure
+/// is a merge operator.
+pub trait MergeOperator: Send + Sync {
+    /// Folds `operand` into the `existing` value of `key`, `None` if the key has no value.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl<F> MergeOperator for F
+where
+    F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        self(key, existing, operand)
+    }
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// Applies a record of `key` on top of its `existing` value
+pub fn apply(
+    operator: Option<&dyn MergeOperator>,
+    kind: RecordKind,
+    key: &[u8],
+    existing: Option<Vec<u8>>,
+    mut value: Vec<u8>,
+) -> Result<Vec<u8>> {
+    match kind {
+        RecordKind::Value => Ok(value),
+        RecordKind::ExpiringValue => Ok(value.split_off(log_entry::EXPIRY_SIZE.min(value.len()))),
+        RecordKind::Increment => increment(existing, &value),
+        RecordKind::Merge => operator
+            .ok_or_else(|| anyhow!(KvError::NoMergeOperator))?
+            .merge(key, existing.as_deref(), &value),
+        // The index never chains these
+        RecordKind::Tombstone | RecordKind::BatchBegin | RecordKind::BatchCommit => {
+            unreachable!("{:?} record in a value chain", kind)
+        }
+    }
+}
+
+/// Adds the little endian i64 `delta` to a decimal integer, a missing value counting as 0
+fn increment(existing: Option<Vec<u8>>, delta: &[u8]) -> Result<Vec<u8>> {
+    let delta = i64::from_le_bytes(delta.try_into().map_err(|_| anyhow!(KvError::NotAnInteger))?);
+    let n = match existing {
+        Some(v) => std::str::from_utf8(&v).ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| anyhow!(KvError::NotAnInteger))?,
+        None => 0,
+    };
+    let n = n.checked_add(delta).ok_or_else(|| anyhow!(KvError::NotAnInteger))?;
+    Ok(n.to_string().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increment() {
+        let incr = |existing: Option<&str>, delta: i64| {
+            apply(None, RecordKind::Increment, b"k", existing.map(|v| v.as_bytes().to_vec()),
+                  delta.to_le_bytes().to_vec())
+        };
+        assert_eq!(incr(None, 5).unwrap(), b"5");
+        assert_eq!(incr(Some("5"), -7).unwrap(), b"-2");
+        assert!(incr(Some("five"), 1).is_err());
+        assert!(incr(Some(&i64::MAX.to_string()), 1).is_err());
+    }
+
+    #[test]
+    fn test_merge_operator() {
+        let append = |_: &[u8], existing: Option<&[u8]>, operand: &[u8]| -> Result<Vec<u8>> {
+            Ok([existing.unwrap_or_default(), operand].concat())
+        };
+        let value = apply(Some(&append), RecordKind::Merge, b"k", Some(b"ab".to_vec()), b"c".to_vec());
+        assert_eq!(value.unwrap(), b"abc");
+        assert!(apply(None, RecordKind::Merge, b"k", None, b"c".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::error::KvError;
+use crate::{KvStore, MergeOperator, Result};
+
+/// Default size at which the active datafile is sealed: 1MB
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Default share of dead bytes in sealed datafiles that triggers compaction
+const DEFAULT_COMPACTION_DEAD_RATIO: f64 = 0.5;
+/// Default amount of dead bytes worth reclaiming: 512KB
+const DEFAULT_COMPACTION_MIN_DEAD_BYTES: u64 = 512 * 1024;
+/// Default number of sealed datafiles past which they are merged regardless of dead bytes
+const DEFAULT_COMPACTION_MAX_SEGMENTS: usize = 16;
+
+/// Tunables used when opening a [`KvStore`](crate::KvStore).
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Size in bytes after which the active datafile is sealed and a new one is started.
+    pub max_segment_size: u64,
+    /// Compact once at least this share of the bytes in sealed datafiles is dead.
+    pub compaction_dead_ratio: f64,
+    /// Never compact for dead bytes unless at least this many bytes can be reclaimed.
+    pub compaction_min_dead_bytes: u64,
+    /// Compact whenever there are more sealed datafiles than this.
+    pub compaction_max_segments: usize,
+    /// Folds operands written by [`KvStore::merge`](crate::KvStore::merge). Merging
+    /// fails without one; [`KvStore::incr_by`](crate::KvStore::incr_by) does not need it.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// When writes are fsynced to disk.
+    pub sync: SyncMode,
+    /// Open an existing store without ever writing to it. Every write fails with
+    /// [`KvError::ReadOnly`](crate::KvError::ReadOnly) and nothing is compacted.
+    pub read_only: bool,
+    /// Create the directory and a new store in it if there is no store yet.
+    /// Otherwise opening fails with [`KvError::StoreNotFound`](crate::KvError::StoreNotFound).
+    pub create_if_missing: bool,
+    /// Fail with [`KvError::StoreExists`](crate::KvError::StoreExists) if the directory already holds a store.
+    pub error_if_exists: bool,
+    /// Data structure holding the in-memory index of keys.
+    pub index: IndexType,
+}
+
+/// When writes to a [`KvStore`](crate::KvStore) are fsynced to disk.
+///
+/// Whatever the mode, [`KvStore::flush`](crate::KvStore::flush) fsyncs every write
+/// so far, and so do sealing a datafile and closing the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// Every write is fsynced before it returns. Writers running at the same
+    /// time share an fsync.
+    Always,
+    /// Every `n`th write fsyncs, along with all the writes before it.
+    EveryN(u64),
+    /// A background thread fsyncs every this many milliseconds.
+    Interval(u64),
+    /// Leave it to the OS. A crash of the machine can lose recent writes.
+    #[default]
+    Never,
+}
+
+/// Data structure holding the in-memory index of every key in a [`KvStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexType {
+    /// An ordered tree. Scans walk the keys in order.
+    #[default]
+    BTree,
+    /// A hash table, with faster point lookups. Scans have to collect and
+    /// sort the keys in range first.
+    Hash,
+}
+
+impl Options {
+    /// Checks that the options make sense together
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg| Err(anyhow!(KvError::InvalidOptions(msg)));
+        if self.max_segment_size == 0 {
+            return invalid("max_segment_size must be positive");
+        }
+        if !(0.0..=1.0).contains(&self.compaction_dead_ratio) {
+            return invalid("compaction_dead_ratio must be between 0 and 1");
+        }
+        if self.compaction_max_segments == 0 {
+            return invalid("compaction_max_segments must be positive");
+        }
+        if matches!(self.sync, SyncMode::EveryN(0) | SyncMode::Interval(0)) {
+            return invalid("sync interval must be positive");
+        }
+        if self.read_only && self.error_if_exists {
+            return invalid("error_if_exists cannot be set in read-only mode");
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            compaction_dead_ratio: DEFAULT_COMPACTION_DEAD_RATIO,
+            compaction_min_dead_bytes: DEFAULT_COMPACTION_MIN_DEAD_BYTES,
+            compaction_max_segments: DEFAULT_COMPACTION_MAX_SEGMENTS,
+            merge_operator: None,
+            sync: SyncMode::default(),
+            read_only: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            index: IndexType::default(),
+        }
+    }
+}
+
+/// Sets up [`Options`] one at a time and opens a [`KvStore`] with them,
+/// returned by [`KvStore::builder`].
+///
+/// ```no_run
+/// # fn main() -> kvs::Result<()> {
+/// let store = kvs::KvStore::builder(".")
+///     .max_segment_size(64 * 1024 * 1024)
+///     .sync(kvs::SyncMode::EveryN(100))
+///     .open()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    options: Options,
+}
+
+impl KvStoreBuilder {
+    pub(crate) fn new(path: &Path) -> KvStoreBuilder {
+        KvStoreBuilder {
+            path: path.to_owned(),
+            options: Options::default(),
+        }
+    }
+
+    /// See [`Options::max_segment_size`].
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.options.max_segment_size = bytes;
+        self
+    }
+
+    /// See [`Options::compaction_dead_ratio`].
+    pub fn compaction_dead_ratio(mut self, ratio: f64) -> Self {
+        self.options.compaction_dead_ratio = ratio;
+        self
+    }
+
+    /// See [`Options::compaction_min_dead_bytes`].
+    pub fn compaction_min_dead_bytes(mut self, bytes: u64) -> Self {
+        self.options.compaction_min_dead_bytes = bytes;
+        self
+    }
+
+    /// See [`Options::compaction_max_segments`].
+    pub fn compaction_max_segments(mut self, segments: usize) -> Self {
+        self.options.compaction_max_segments = segments;
+        self
+    }
+
+    /// See [`Options::merge_operator`].
+    pub fn merge_operator(mut self, operator: impl MergeOperator + 'static) -> Self {
+        self.options.merge_operator = Some(Arc::new(operator));
+        self
+    }
+
+    /// See [`Options::sync`].
+    pub fn sync(mut self, sync: SyncMode) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    /// See [`Options::read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// See [`Options::create_if_missing`].
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.options.create_if_missing = create;
+        self
+    }
+
+    /// See [`Options::error_if_exists`].
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.options.error_if_exists = error;
+        self
+    }
+
+    /// See [`Options::index`].
+    pub fn index(mut self, index: IndexType) -> Self {
+        self.options.index = index;
+        self
+    }
+
+    /// Validates the options and opens the store.
+    pub fn open(self) -> Result<KvStore> {
+        KvStore::open_with(&self.path, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(Options::default().validate().is_ok());
+        let invalid = [
+            Options { max_segment_size: 0, ..Options::default() },
+            Options { compaction_dead_ratio: 1.5, ..Options::default() },
+            Options { compaction_dead_ratio: f64::NAN, ..Options::default() },
+            Options { compaction_max_segments: 0, ..Options::default() },
+            Options { sync: SyncMode::EveryN(0), ..Options::default() },
+            Options { sync: SyncMode::Interval(0), ..Options::default() },
+            Options { read_only: true, error_if_exists: true, ..Options::default() },
+        ];
+        for options in invalid {
+            let err = options.validate().unwrap_err();
+            assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::InvalidOptions(_))), "{:?}", options);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bincode::{Decode, Encode};
+
+use crate::error::KvError;
+use crate::index;
+use crate::log_entry;
+use crate::{CasMismatch, CasResult, KvsEngine, Result};
+
+/// Address `kvs-server` listens on and `kvs-client` connects to by default
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+/// Largest message accepted from the wire: 64MB
+const MAX_MESSAGE_SIZE: u32 = 64 * 1024 * 1024;
+
+/*
+* Client and server exchange length-prefixed messages over TCP.
+* Message Format :
+* len | body
+* u32 | bincode encoded Request or Response
+* len is the little endian size of the body. A client sends a Request and reads
+* back exactly one Response, and may send any number of requests on a connection.
+*/
+/// A request sent by `kvs-client`
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub enum Request {
+    /// Gets the value of a key
+    Get {
+        /// The key.
+        key: Vec<u8>,
+    },
+    /// Sets the value of a key
+    Set {
+        /// The key.
+        key: Vec<u8>,
+        /// The value.
+        value: Vec<u8>,
+    },
+    /// Removes a key
+    Remove {
+        /// The key.
+        key: Vec<u8>,
+    },
+    /// Sets the value of a key that expires after `ttl_millis` milliseconds
+    SetWithTtl {
+        /// The key.
+        key: Vec<u8>,
+        /// The value.
+        value: Vec<u8>,
+        /// Time to live in milliseconds.
+        ttl_millis: u64,
+    },
+    /// Gets the time a key has left before it expires
+    Ttl {
+        /// The key.
+        key: Vec<u8>,
+    },
+    /// Lists key-value pairs in key order
+    Scan(ScanQuery),
+    /// Replaces the value of a key if it currently is `expected`
+    CompareAndSwap {
+        /// The key.
+        key: Vec<u8>,
+        /// The value the key must have, `None` if it must not exist.
+        expected: Option<Vec<u8>>,
+        /// The value to set, `None` to remove the key.
+        new: Option<Vec<u8>>,
+    },
+}
+
+/// A range scan, as run by the `scan` subcommand
+#[derive(Debug, Encode, Decode, Clone, PartialEq, Default)]
+pub struct ScanQuery {
+    /// First key to list, from the first key in the store if `None`
+    pub start: Option<Vec<u8>>,
+    /// Key to stop before, up to the last key in the store if `None`
+    pub end: Option<Vec<u8>>,
+    /// Only list keys starting with this prefix
+    pub prefix: Option<Vec<u8>>,
+    /// List in reverse key order
+    pub reverse: bool,
+    /// List at most this many pairs
+    pub limit: Option<u64>,
+}
+
+impl ScanQuery {
+    /// Runs the scan against an engine
+    pub fn run<E: KvsEngine>(&self, engine: &E) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut start = self.start.clone().map_or(Bound::Unbounded, Bound::Included);
+        let mut end = self.end.clone().map_or(Bound::Unbounded, Bound::Excluded);
+        if let Some(prefix) = &self.prefix {
+            // Narrow the range down to the keys under the prefix
+            let (prefix_start, prefix_end) = index::prefix_range(prefix);
+            if self.start.as_ref().is_none_or(|start| start < prefix) {
+                start = prefix_start;
+            }
+            if let Bound::Excluded(prefix_end) = prefix_end {
+                if self.end.as_ref().is_none_or(|end| *end > prefix_end) {
+                    end = Bound::Excluded(prefix_end);
+                }
+            }
+        }
+        if matches!((&start, &end), (Bound::Included(start), Bound::Excluded(end)) if start >= end) {
+            return Ok(Vec::new());
+        }
+        let limit = self.limit.map_or(usize::MAX, |limit| limit.try_into().unwrap_or(usize::MAX));
+        engine.scan_limit((start, end), self.reverse, limit)
+    }
+}
+
+/// The server's answer to a [`Request`]
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub enum Response {
+    /// The request succeeded. Carries the value for `Get`, `None` otherwise.
+    Ok(Option<Vec<u8>>),
+    /// The key-value pairs listed by a `Scan`
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    /// A `CompareAndSwap` found another value, carrying the current one
+    Mismatch(Option<Vec<u8>>),
+    /// Milliseconds left before the key of a `Ttl` expires, `None` if it never does
+    Ttl(Option<u64>),
+    /// The key does not exist
+    KeyNotFound,
+    /// The request failed on the server
+    Err(String),
+}
+
+impl Response {
+    /// Builds the response to send back for an engine error
+    pub fn from_error(e: &anyhow::Error) -> Response {
+        match e.downcast_ref::<KvError>() {
+            Some(KvError::KeyNotFound) => Response::KeyNotFound,
+            _ => Response::Err(e.to_string()),
+        }
+    }
+
+    /// Turns error responses back into the errors the server saw
+    pub fn into_result(self) -> Result<Response> {
+        match self {
+            Response::KeyNotFound => Err(anyhow!(KvError::KeyNotFound)),
+            Response::Err(msg) => Err(anyhow!(KvError::Server(msg))),
+            response => Ok(response),
+        }
+    }
+
+    /// Unwraps the value of a successful `Get`, `Set` or `Remove`
+    pub fn into_value(self) -> Result<Option<Vec<u8>>> {
+        match self.into_result()? {
+            Response::Ok(value) => Ok(value),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Unwraps the outcome of a `CompareAndSwap`
+    pub fn into_cas_result(self) -> Result<CasResult> {
+        match self.into_result()? {
+            Response::Ok(_) => Ok(CasResult::Ok(())),
+            Response::Mismatch(current) => Ok(Err(CasMismatch { current })),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Unwraps the time left of a successful `Ttl`
+    pub fn into_ttl(self) -> Result<Option<Duration>> {
+        match self.into_result()? {
+            Response::Ttl(millis) => Ok(millis.map(Duration::from_millis)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Unwraps the pairs of a successful `Scan`
+    pub fn into_pairs(self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.into_result()? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+}
+
+/// Writes one length-prefixed message and flushes the writer
+pub fn write_message<T: Encode>(writer: &mut impl Write, message: &T) -> Result<()> {
+    let body = bincode::encode_to_vec(message, log_entry::bincode_config())?;
+    if body.len() > MAX_MESSAGE_SIZE as usize {
+        return Err(anyhow!("Message of {} bytes is too large", body.len()));
+    }
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads one length-prefixed message.
+/// Returns `None` if the peer closed the connection between messages.
+pub fn read_message<T: Decode<()>>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Message of {} bytes is too large", len));
+    }
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body)?;
+    let (message, read) = bincode::decode_from_slice(&body, log_entry::bincode_config())?;
+    if read != body.len() {
+        return Err(anyhow!("Trailing bytes after message"));
+    }
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut buf = Vec::new();
+        let request = Request::Set { key: b"k1".to_vec(), value: vec![0xff, 0x00] };
+        write_message(&mut buf, &request).unwrap();
+        write_message(&mut buf, &Response::KeyNotFound).unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_message::<Request>(&mut reader).unwrap(), Some(request));
+        assert_eq!(read_message::<Response>(&mut reader).unwrap(), Some(Response::KeyNotFound));
+        assert_eq!(read_message::<Response>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Request::Get { key: b"k1".to_vec() }).unwrap();
+        buf.pop();
+        assert!(read_message::<Request>(&mut buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,497 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+
+use anyhow::anyhow;
+use log::debug;
+
+use crate::error::KvError;
+use crate::{KvsEngine, Result};
+
+/// Largest bulk string accepted from a client: 64MB
+const MAX_BULK_SIZE: usize = 64 * 1024 * 1024;
+/// Most arguments accepted in a single command
+const MAX_ARGS: usize = 1024 * 1024;
+/// Number of keys `SCAN` returns per call unless told otherwise
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/*
+* RESP2 is the Redis serialization protocol. Clients send commands as arrays of
+* bulk strings, or as a single line of space separated words (inline commands):
+* *2\r\n$3\r\nGET\r\n$3\r\nkey\r\n
+* Replies are one of:
+* +simple string\r\n | -error\r\n | :integer\r\n | $len\r\nbytes\r\n | $-1\r\n (nil) | *len\r\n...
+* See https://redis.io/docs/reference/protocol-spec/
+*/
+/// A RESP2 reply
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, `None` being the nil reply
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    fn error(msg: impl Into<String>) -> Value {
+        Value::Error(format!("ERR {}", msg.into()))
+    }
+
+    fn bulk(bytes: impl Into<Vec<u8>>) -> Value {
+        Value::Bulk(Some(bytes.into()))
+    }
+
+    /// Encodes the reply onto `writer`
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Value::Error(s) => write!(writer, "-{}\r\n", s)?,
+            Value::Integer(i) => write!(writer, ":{}\r\n", i)?,
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Value::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads one command from the client.
+/// Returns `None` if the client closed the connection between commands.
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let args: Vec<Vec<u8>> = line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect();
+            // Blank inline commands are ignored, like redis does
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+        let count = parse_len(&line[1..], MAX_ARGS)?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = read_line(reader)?.ok_or_else(|| anyhow!("unexpected end of stream"))?;
+            if header.first() != Some(&b'$') {
+                return Err(anyhow!("expected '$', got '{}'", String::from_utf8_lossy(&header)));
+            }
+            let len = parse_len(&header[1..], MAX_BULK_SIZE)?;
+            let mut arg = vec![0u8; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(anyhow!("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+/// Reads a line without its line ending, `None` at end of stream
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // Bound the line so a client cannot make us buffer without end
+    let read = reader.take(MAX_BULK_SIZE as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("line too long or not terminated"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    let len = std::str::from_utf8(digits).ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .ok_or_else(|| anyhow!("invalid length '{}'", String::from_utf8_lossy(digits)))?;
+    if len > max {
+        return Err(anyhow!("length {} is too large", len));
+    }
+    Ok(len)
+}
+
+/// Answers RESP commands on one connection until the client hangs up or sends `QUIT`
+pub fn handle<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // Redis replies with a protocol error and drops the connection
+                Value::error(format!("Protocol error: {}", e)).write(&mut writer)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        debug!("{} sent {}", peer, String::from_utf8_lossy(&args.join(&b' ')));
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit { Value::ok() } else { execute(&engine, &args) };
+        reply.write(&mut writer)?;
+        writer.flush()?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs a single command against the engine and builds its reply
+pub fn execute<E: KvsEngine>(engine: &E, args: &[Vec<u8>]) -> Value {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let res = match name.as_str() {
+        "PING" => match args {
+            [] => Ok(Value::Simple("PONG".to_owned())),
+            [msg] => Ok(Value::bulk(msg.clone())),
+            _ => Err(arity(&name)),
+        },
+        "GET" => match args {
+            [key] => engine.get_bytes(key).map(Value::Bulk),
+            _ => Err(arity(&name)),
+        },
+        "SET" => match args {
+            [key, value] => engine.set_bytes(key, value).map(|_| Value::ok()),
+            [_, _, ..] => Err(anyhow!("syntax error")),
+            _ => Err(arity(&name)),
+        },
+        "SETNX" => match args {
+            [key, value] => engine.set_if_absent(key, value).map(|res| Value::Integer(res.is_ok() as i64)),
+            _ => Err(arity(&name)),
+        },
+        // CAS key expected new -> [1 | 0, current value]
+        "CAS" => match args {
+            [key, expected, new] => engine.compare_and_swap(key, Some(expected), Some(new)).map(|res| match res {
+                Ok(()) => Value::Array(vec![Value::Integer(1), Value::bulk(new.clone())]),
+                Err(mismatch) => Value::Array(vec![Value::Integer(0), Value::Bulk(mismatch.current)]),
+            }),
+            _ => Err(arity(&name)),
+        },
+        "DELIFEQ" => match args {
+            [key, value] => engine.remove_if_equals(key, value).map(|res| Value::Integer(res.is_ok() as i64)),
+            _ => Err(arity(&name)),
+        },
+        "DEL" if !args.is_empty() => count(args, |key| match engine.remove_bytes(key) {
+            Ok(()) => Ok(true),
+            Err(e) if matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)) => Ok(false),
+            Err(e) => Err(e),
+        }),
+        "EXISTS" if !args.is_empty() => count(args, |key| Ok(engine.get_bytes(key)?.is_some())),
+        "KEYS" => match args {
+            [pattern] => keys(engine).map(|keys| {
+                Value::Array(keys.into_iter()
+                    .filter(|key| glob_match(pattern, key))
+                    .map(Value::bulk)
+                    .collect())
+            }),
+            _ => Err(arity(&name)),
+        },
+        "SCAN" if !args.is_empty() => scan(engine, args),
+        "DBSIZE" => match args {
+            [] => keys(engine).map(|keys| Value::Integer(keys.len() as i64)),
+            _ => Err(arity(&name)),
+        },
+        "FLUSHDB" => match args {
+            [] => flush_db(engine).map(|_| Value::ok()),
+            // ASYNC and SYNC both flush right away
+            [mode] if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") => {
+                flush_db(engine).map(|_| Value::ok())
+            }
+            _ => Err(anyhow!("syntax error")),
+        },
+        // redis-cli asks for command docs on startup
+        "COMMAND" => Ok(Value::Array(Vec::new())),
+        "DEL" | "EXISTS" | "SCAN" => Err(arity(&name)),
+        _ => Err(anyhow!("unknown command '{}'", name.to_ascii_lowercase())),
+    };
+    res.unwrap_or_else(|e| Value::error(e.to_string()))
+}
+
+fn arity(name: &str) -> anyhow::Error {
+    anyhow!("wrong number of arguments for '{}' command", name.to_ascii_lowercase())
+}
+
+/// Counts the keys in `args` that `f` returns true for
+fn count(args: &[Vec<u8>], mut f: impl FnMut(&[u8]) -> Result<bool>) -> Result<Value> {
+    let mut n = 0;
+    for key in args {
+        if f(key)? {
+            n += 1;
+        }
+    }
+    Ok(Value::Integer(n))
+}
+
+/// All keys in the store, in key order
+fn keys<E: KvsEngine>(engine: &E) -> Result<Vec<Vec<u8>>> {
+    engine.scan_keys(.., usize::MAX)
+}
+
+fn flush_db<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in keys(engine)? {
+        match engine.remove_bytes(key) {
+            Err(e) if !matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)) => {
+                return Err(e)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+/// The cursor is the last key returned, hex encoded, so the next call picks up
+/// right after it. It is 0 to start and once done.
+fn scan<E: KvsEngine>(engine: &E, args: &[Vec<u8>]) -> Result<Value> {
+    let start = match args[0].as_slice() {
+        b"0" => Bound::Unbounded,
+        cursor => Bound::Excluded(hex_decode(cursor).ok_or_else(|| anyhow!("invalid cursor"))?),
+    };
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].chunks(2);
+    for option in options.by_ref() {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                count = std::str::from_utf8(value).ok()
+                    .and_then(|s| s.parse().ok())
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| anyhow!("value is not an integer or out of range"))?;
+            }
+            _ => return Err(anyhow!("syntax error")),
+        }
+    }
+    let page = engine.scan_keys((start, Bound::Unbounded), count)?;
+    let next = match page.last() {
+        Some(last) if page.len() == count => hex_encode(last),
+        _ => "0".to_owned(),
+    };
+    Ok(Value::Array(vec![
+        Value::bulk(next),
+        Value::Array(page.into_iter()
+            .filter(|key| glob_match(pattern, key))
+            .map(Value::bulk)
+            .collect()),
+    ]))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Matches `s` against a redis style glob pattern: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+///
+/// On a mismatch only the last `*` is retried, matching one more byte, so the
+/// cost stays within the pattern length times the input length.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Pattern position right after the last `*`, and the input position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], s[i]) {
+            p += len;
+            i += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, matched)) => {
+                p = after_star;
+                i = matched + 1;
+                star = Some((after_star, i));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Matches the byte `c` against the token at the start of `pattern`, which is not `*`.
+/// Returns the length of the token if it matches.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', class @ ..] => {
+            let (matched, len) = match_class(class, c);
+            matched.then_some(1 + len)
+        }
+        [b'\\', x, ..] => (*x == c).then_some(2),
+        [x, ..] => (*x == c).then_some(1),
+    }
+}
+
+/// Matches `c` against a character class, given the pattern right after its `[`.
+/// Returns whether it matched and the length of the class up to and including its `]`.
+fn match_class(class: &[u8], c: u8) -> (bool, usize) {
+    let (negate, mut i) = match class.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    loop {
+        match &class[i..] {
+            // An unterminated class matches as if it was closed
+            [] => break,
+            [b']', ..] => {
+                i += 1;
+                break;
+            }
+            [b'\\', x, ..] => {
+                matched |= *x == c;
+                i += 2;
+            }
+            [lo, b'-', hi, ..] => {
+                let (lo, hi) = if lo <= hi { (*lo, *hi) } else { (*hi, *lo) };
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+            [x, ..] => {
+                matched |= *x == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negate, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryEngine;
+
+    use super::*;
+
+    fn command(line: &str) -> Vec<Vec<u8>> {
+        line.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn encode(value: &Value) -> String {
+        let mut buf = Vec::new();
+        value.write(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_read_command() {
+        let input = b"*3\r\n$3\r\nSET\r\n$4\r\nk\r\n1\r\n$0\r\n\r\n\r\nget  k1\r\n";
+        let mut reader = &input[..];
+        assert_eq!(read_command(&mut reader).unwrap(),
+                   Some(vec![b"SET".to_vec(), b"k\r\n1".to_vec(), Vec::new()]));
+        assert_eq!(read_command(&mut reader).unwrap(), Some(command("get k1")));
+        assert_eq!(read_command(&mut reader).unwrap(), None);
+        assert!(read_command(&mut &b"*1\r\n:1\r\n"[..]).is_err());
+        assert!(read_command(&mut &b"*1\r\n$3\r\nGE"[..]).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&Value::Array(vec![
+            Value::ok(),
+            Value::error("oops"),
+            Value::Integer(-2),
+            Value::Bulk(None),
+            Value::bulk("v1"),
+        ])), "*5\r\n+OK\r\n-ERR oops\r\n:-2\r\n$-1\r\n$2\r\nv1\r\n");
+    }
+
+    #[test]
+    fn test_execute() {
+        let engine = MemoryEngine::new();
+        let run = |line: &str| execute(&engine, &command(line));
+        assert_eq!(run("PING"), Value::Simple("PONG".to_owned()));
+        assert_eq!(run("set k1 v1"), Value::ok());
+        assert_eq!(run("SET k2 v2"), Value::ok());
+        assert_eq!(run("GET k1"), Value::bulk("v1"));
+        assert_eq!(run("GET k3"), Value::Bulk(None));
+        assert_eq!(run("EXISTS k1 k2 k3 k1"), Value::Integer(3));
+        assert_eq!(run("DBSIZE"), Value::Integer(2));
+        assert_eq!(run("KEYS *2"), Value::Array(vec![Value::bulk("k2")]));
+        // The cursor is the last key returned, hex encoded
+        assert_eq!(run("SCAN 0 COUNT 1"),
+                   Value::Array(vec![Value::bulk("6b31"), Value::Array(vec![Value::bulk("k1")])]));
+        assert_eq!(run("SCAN 6b31 COUNT 2"),
+                   Value::Array(vec![Value::bulk("0"), Value::Array(vec![Value::bulk("k2")])]));
+        assert_eq!(run("SCAN 0 MATCH *2"),
+                   Value::Array(vec![Value::bulk("0"), Value::Array(vec![Value::bulk("k2")])]));
+        assert_eq!(run("SCAN 6b3"), Value::error("invalid cursor"));
+        assert_eq!(run("DEL k1 k3"), Value::Integer(1));
+        assert_eq!(execute(&engine, &[b"SET".to_vec(), vec![0xff], vec![0x00]]), Value::ok());
+        assert_eq!(execute(&engine, &[b"GET".to_vec(), vec![0xff]]), Value::bulk(vec![0x00]));
+        assert_eq!(run("SETNX k2 v3"), Value::Integer(0));
+        assert_eq!(run("SETNX k3 v3"), Value::Integer(1));
+        assert_eq!(run("CAS k3 v1 v4"), Value::Array(vec![Value::Integer(0), Value::bulk("v3")]));
+        assert_eq!(run("CAS k3 v3 v4"), Value::Array(vec![Value::Integer(1), Value::bulk("v4")]));
+        assert_eq!(run("DELIFEQ k3 v3"), Value::Integer(0));
+        assert_eq!(run("DELIFEQ k3 v4"), Value::Integer(1));
+        assert_eq!(run("GET k3"), Value::Bulk(None));
+        assert_eq!(run("FLUSHDB"), Value::ok());
+        assert_eq!(run("DBSIZE"), Value::Integer(0));
+
+        assert_eq!(run("GET"), Value::error("wrong number of arguments for 'get' command"));
+        assert_eq!(run("SET k1 v1 EX 10"), Value::error("syntax error"));
+        assert_eq!(run("HGET h k"), Value::error("unknown command 'hget'"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"k*1", b"key1"));
+        assert!(!glob_match(b"k*1", b"key2"));
+        assert!(glob_match(b"k?y", b"key"));
+        assert!(glob_match(b"k[ae]y", b"key"));
+        assert!(!glob_match(b"k[^ae]y", b"key"));
+        assert!(glob_match(b"key[0-9]", b"key7"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*a*b", b"xaxxab"));
+        assert!(!glob_match(b"*a*b", b"xaxxa"));
+        assert!(glob_match(b"a**", b"a"));
+        assert!(glob_match(b"*[0-9]", b"key10"));
+        assert!(glob_match(b"k[ae", b"ka"));
+        // Many stars against a long input that almost matches must not blow up
+        let pattern = [b"a*".repeat(30), b"b".to_vec()].concat();
+        assert!(!glob_match(&pattern, &[b'a'; 1000]));
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+use crate::cli::Protocol;
+use crate::http;
+use crate::protocol::{self, Request, Response};
+use crate::resp;
+use crate::{KvsEngine, Result};
+
+/// Serves a [`KvsEngine`] over TCP, to `kvs-client`s, Redis clients or over HTTP.
+///
+/// Every connection is handled on its own thread with a clone of the engine.
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+    protocol: Protocol,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Creates a server for the given engine speaking the kvs protocol.
+    pub fn new(engine: E) -> Self {
+        Self::with_protocol(engine, Protocol::Kvs)
+    }
+
+    /// Creates a server for the given engine speaking the given protocol.
+    pub fn with_protocol(engine: E, protocol: Protocol) -> Self {
+        KvsServer { engine, protocol }
+    }
+
+    /// Listens on `addr` and serves clients until the process exits.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves clients connecting to an already bound listener.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        info!("Listening on {} ({:?} protocol)", listener.local_addr()?, self.protocol);
+        let handler: fn(E, TcpStream) -> Result<()> = match self.protocol {
+            Protocol::Kvs => handle,
+            Protocol::Resp => resp::handle,
+            // tiny_http manages its own connections
+            Protocol::Http => return http::serve(self.engine, listener),
+        };
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            thread::spawn(move || {
+                if let Err(e) = handler(engine, stream) {
+                    error!("Connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Answers requests on one connection until the client hangs up
+fn handle<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(request) = protocol::read_message::<Request>(&mut reader)? {
+        debug!("{} sent {:?}", peer, request);
+        let res = match request {
+            Request::Get { key } => engine.get_bytes(key).map(Response::Ok),
+            Request::Set { key, value } => engine.set_bytes(key, value).map(|_| Response::Ok(None)),
+            Request::Remove { key } => engine.remove_bytes(key).map(|_| Response::Ok(None)),
+            Request::SetWithTtl { key, value, ttl_millis } => engine
+                .set_bytes_with_ttl(key, value, Duration::from_millis(ttl_millis))
+                .map(|_| Response::Ok(None)),
+            Request::Ttl { key } => engine.ttl_bytes(key)
+                .map(|ttl| Response::Ttl(ttl.map(|ttl| ttl.as_millis() as u64))),
+            Request::Scan(query) => query.run(&engine).map(Response::Pairs),
+            Request::CompareAndSwap { key, expected, new } => {
+                engine.compare_and_swap(key, expected.as_deref(), new.as_deref())
+                    .map(|res| res.map_or_else(|e| Response::Mismatch(e.current), |_| Response::Ok(None)))
+            }
+        };
+        let response = res.unwrap_or_else(|e| Response::from_error(&e));
+        protocol::write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
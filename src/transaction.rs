@@ -0,0 +1,88 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::anyhow;
+
+use crate::batch::WriteBatch;
+use crate::engines;
+use crate::error::KvError;
+use crate::{KvStore, Result};
+
+/// Number of times [`KvStore::transaction`] reruns a conflicting transaction before giving up
+pub const MAX_TRANSACTION_RETRIES: usize = 16;
+
+/// A read-modify-write transaction, handed to the closure given to [`KvStore::transaction`].
+///
+/// Reads see the transaction's own writes. Everything else is read from the store,
+/// and the version of each key read is remembered to check for conflicts on commit.
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // Sequence number of each key as first read, `None` if it did not exist
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // Buffered writes, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Self {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(engines::to_string).transpose()
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Removes a key. Fails with [`KvError::KeyNotFound`] if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key)
+    }
+
+    /// Gets the value of a key as bytes, or `None` if the key does not exist.
+    pub fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let versioned = self.store.get_versioned(key)?;
+        self.reads.entry(key.to_vec()).or_insert(versioned.as_ref().map(|(seq, _)| *seq));
+        Ok(versioned.map(|(_, value)| value))
+    }
+
+    /// Sets the value of a key. Keys and values can be any bytes.
+    pub fn set_bytes(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.writes.insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+        Ok(())
+    }
+
+    /// Removes a key. Fails with [`KvError::KeyNotFound`] if the key does not exist.
+    pub fn remove_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        // Whether the key exists is a read like any other
+        if self.get_bytes(key)?.is_none() {
+            return Err(anyhow!(KvError::KeyNotFound));
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Splits the transaction into its read set and its writes as a batch
+    pub(crate) fn into_parts(self) -> (HashMap<Vec<u8>, Option<u64>>, WriteBatch) {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        (self.reads, batch)
+    }
+}
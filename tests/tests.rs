@@ -1,5 +1,11 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CasMismatch, DataFileError, IndexType, KvError, KvStore, KvsClient, KvsEngine, KvsServer, MemoryEngine, Options, Protocol, SyncMode,
+    Result, ScanQuery, WriteBatch, MAX_MERGE_OPERANDS, MAX_TRANSACTION_RETRIES,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
 use std::process::Command;
@@ -17,7 +23,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +34,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +47,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +60,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +71,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +86,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +100,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +114,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +127,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +142,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +163,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +178,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -181,7 +187,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +197,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +208,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +217,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +229,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +245,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +253,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +265,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -268,6 +274,12 @@ fn compaction() -> Result<()> {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
             })
+            // The background compactor may remove a file between listing and stat
+            .filter(|res| {
+                !res.as_ref().is_err_and(|e| {
+                    e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+                })
+            })
             .sum();
         len.expect("fail to get directory size")
     };
@@ -289,7 +301,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -298,4 +310,1833 @@ fn compaction() -> Result<()> {
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+// Writes past the segment size should roll over into new datafiles,
+// and every value should still be readable after reopening.
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let datafiles = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .count();
+    assert!(datafiles > 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A store written before the log was split into segments should be refused
+// rather than have its `main.dat` replayed as a segment.
+#[test]
+fn legacy_datafile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = temp_dir.path().join("main.dat");
+    std::fs::write(&legacy, b"legacy records")?;
+    let err = KvStore::open(temp_dir.path()).err().expect("legacy store opened");
+    assert!(matches!(err.downcast_ref::<DataFileError>(), Some(DataFileError::LegacyDatafile)));
+    assert_eq!(std::fs::read(&legacy)?, b"legacy records");
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+    Ok(())
+}
+
+// A flipped bit in a stored value should surface as a corruption error,
+// not as silently wrong data.
+#[test]
+fn corrupted_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let datafile = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .find(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .expect("no datafile written");
+    let mut bytes = std::fs::read(datafile.path())?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(datafile.path(), bytes)?;
+
+    let err = match KvStore::open(temp_dir.path()) {
+        Ok(store) => store.get("key1".to_owned()).unwrap_err(),
+        Err(e) => e,
+    };
+    // The first record, right after the 32 byte header
+    assert!(matches!(
+        err.downcast_ref::<DataFileError>(),
+        Some(DataFileError::Corrupted { offset: 32, .. })
+    ));
+    Ok(())
+}
+
+// A record cut short by a crash should be truncated on open,
+// and writes made afterwards should survive the next restart.
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let datafile = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .find(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .expect("no datafile written");
+    let len = std::fs::metadata(datafile.path())?.len();
+    let f = std::fs::OpenOptions::new().write(true).open(datafile.path())?;
+    f.set_len(len - 4)?;
+    drop(f);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Sealed datafiles are fsynced whole, so a record cut short in one is not
+// left over from a crash. It should be reported as corruption, not truncated.
+#[test]
+fn torn_sealed_datafile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let mut datafiles: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .map(|e| e.path().to_owned())
+        .collect();
+    datafiles.sort();
+    assert!(datafiles.len() > 1);
+    let sealed = &datafiles[0];
+    std::fs::remove_file(sealed.with_extension("hint"))?;
+    let len = std::fs::metadata(sealed)?.len();
+    let f = std::fs::OpenOptions::new().write(true).open(sealed)?;
+    f.set_len(len - 4)?;
+    drop(f);
+
+    let err = KvStore::open_with(temp_dir.path(), options).err().expect("opened a torn sealed datafile");
+    assert!(matches!(err.downcast_ref::<DataFileError>(), Some(DataFileError::Corrupted { .. })));
+    assert_eq!(std::fs::metadata(sealed)?.len(), len - 4);
+    Ok(())
+}
+
+// Sealed datafiles should get hint files, and a damaged or missing hint
+// should fall back to scanning the datafile.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let hints: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "hint"))
+        .map(|e| e.path().to_owned())
+        .collect();
+    assert!(!hints.is_empty());
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+    drop(store);
+
+    // Damage one hint and delete the rest
+    let mut bytes = std::fs::read(&hints[0])?;
+    bytes[0] ^= 0x01;
+    std::fs::write(&hints[0], bytes)?;
+    for hint in &hints[1..] {
+        std::fs::remove_file(hint)?;
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Reads and writes should keep working while sealed datafiles are merged
+// in the background, and the merge should keep the number of datafiles bounded.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 4 * 1024,
+        compaction_min_dead_bytes: 0,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-{}", key_id, iter))
+            );
+        }
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let datafiles = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .count();
+    assert!(datafiles <= 4, "{} datafiles left after compaction", datafiles);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-49", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Temporary files left by an interrupted compaction should be removed on open
+// without touching the data.
+#[test]
+fn leftover_compaction_output() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("00000000000000000002.dat.tmp");
+    std::fs::write(&leftover, b"half written")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Sealed datafiles holding only live data should be left alone,
+// while `compact` should merge everything on demand.
+#[test]
+fn compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: 0,
+        compaction_max_segments: usize::MAX,
+        ..Options::default()
+    };
+    let datafiles = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+            .count()
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let unique = datafiles();
+    assert!(unique > 4);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.compact()?;
+    assert!(datafiles() <= 2);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // With nothing written since, the active datafile holds only its header and stays put
+    let names = || -> Vec<_> {
+        let mut names: Vec<_> = std::fs::read_dir(temp_dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().ends_with(".dat"))
+            .collect();
+        names.sort();
+        names
+    };
+    let before = names();
+    store.compact()?;
+    assert_eq!(names(), before);
+    Ok(())
+}
+
+// An empty string is a value like any other and is not mistaken for a removal.
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Exercises the `KvsEngine` contract shared by every backend
+fn check_engine<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("c".to_owned(), "3".to_owned())?;
+    engine.set("a".to_owned(), "11".to_owned())?;
+    assert_eq!(engine.get("a".to_owned())?, Some("11".to_owned()));
+    assert_eq!(engine.get("d".to_owned())?, None);
+
+    engine.remove("c".to_owned())?;
+    assert!(engine.remove("c".to_owned()).is_err());
+    engine.set("d".to_owned(), "4".to_owned())?;
+
+    assert_eq!(
+        engine.scan(..)?,
+        vec![
+            ("a".to_owned(), "11".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+            ("d".to_owned(), "4".to_owned()),
+        ]
+    );
+    assert_eq!(
+        engine.scan("b".to_owned().."d".to_owned())?,
+        vec![("b".to_owned(), "2".to_owned())]
+    );
+    assert_eq!(engine.scan_keys(b"b".to_vec().., 10)?, vec![b"b".to_vec(), b"d".to_vec()]);
+    assert_eq!(engine.scan_keys(.., 2)?, vec![b"a".to_vec(), b"b".to_vec()]);
+    engine.set_bytes(b"\xff", b"\x00\xfe")?;
+    assert_eq!(engine.get_bytes(b"\xff")?, Some(b"\x00\xfe".to_vec()));
+    assert_eq!(engine.scan_bytes(b"\xf0".to_vec()..)?, vec![(b"\xff".to_vec(), b"\x00\xfe".to_vec())]);
+    assert_eq!(engine.scan_limit(.., true, 2)?, vec![
+        (b"\xff".to_vec(), b"\x00\xfe".to_vec()),
+        (b"d".to_vec(), b"4".to_vec()),
+    ]);
+    assert_eq!(engine.scan_limit(b"b".to_vec().., false, 1)?, vec![(b"b".to_vec(), b"2".to_vec())]);
+    engine.remove_bytes(b"\xff")?;
+
+    assert_eq!(engine.compare_and_swap(b"a", Some(b"1"), Some(b"12"))?,
+               Err(CasMismatch { current: Some(b"11".to_vec()) }));
+    assert_eq!(engine.compare_and_swap(b"a", Some(b"11"), Some(b"12"))?, Ok(()));
+    assert_eq!(engine.set_if_absent(b"a", b"13")?, Err(CasMismatch { current: Some(b"12".to_vec()) }));
+    assert_eq!(engine.set_if_absent(b"e", b"5")?, Ok(()));
+    assert_eq!(engine.remove_if_equals(b"e", b"4")?, Err(CasMismatch { current: Some(b"5".to_vec()) }));
+    assert_eq!(engine.remove_if_equals(b"e", b"5")?, Ok(()));
+    assert_eq!(engine.compare_and_swap(b"e", Some(b"5"), None)?, Err(CasMismatch { current: None }));
+    assert_eq!(engine.get("e".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"f", b"6").delete(b"d").put(b"g", b"7").delete(b"g");
+    engine.write(batch)?;
+    assert_eq!(engine.get("f".to_owned())?, Some("6".to_owned()));
+    assert_eq!(engine.get("d".to_owned())?, None);
+    assert_eq!(engine.get("g".to_owned())?, None);
+    engine.flush()
+}
+
+#[test]
+fn kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_engine(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(KvsEngine::scan(&store, ..)?.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryEngine::new();
+    check_engine(&engine)?;
+    // Keys never expire
+    let err = engine.set_with_ttl("a".to_owned(), "1".to_owned(), Duration::from_secs(1)).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::Unsupported(_))));
+    assert_eq!(engine.ttl("a".to_owned())?, None);
+    Ok(())
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_engine(&kvs::SledEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn cli_engine_flag() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--engine", "bogus"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Clones of a store share it across threads while segments roll over and compact
+#[test]
+fn concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 4 * 1024,
+        compaction_min_dead_bytes: 0,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for round in 0..50 {
+                    for i in 0..10 {
+                        store.set(format!("key{}_{}", t, i), format!("value{}_{}", round, i))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    for i in 0..10 {
+                        if let Some(value) = store.get(format!("key{}_{}", t, i))? {
+                            assert!(value.starts_with("value") && value.ends_with(&format!("_{}", i)));
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for t in 0..4 {
+            for i in 0..10 {
+                assert_eq!(store.get(format!("key{}_{}", t, i))?, Some(format!("value49_{}", i)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), options)?)
+}
+
+#[test]
+fn client_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    std::thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    // A second connection sees the same store
+    let mut other = KvsClient::connect(addr)?;
+    other.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    let err = client.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)));
+    Ok(())
+}
+
+// Reserves a free port on localhost for a server process to listen on
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr().to_string();
+    let mut server = std::process::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Key not found"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_client_no_server() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &free_addr().to_string()])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn resp_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::with_protocol(KvStore::open(temp_dir.path())?, Protocol::Resp);
+    std::thread::spawn(move || server.serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut reply = |request: &[u8], lines: usize| -> Result<String> {
+        stream.write_all(request)?;
+        let mut buf = String::new();
+        for _ in 0..lines {
+            reader.read_line(&mut buf)?;
+        }
+        Ok(buf)
+    };
+    assert_eq!(reply(b"PING\r\n", 1)?, "+PONG\r\n");
+    assert_eq!(reply(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", 1)?, "+OK\r\n");
+    assert_eq!(reply(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", 2)?, "$6\r\nvalue1\r\n");
+    assert_eq!(reply(b"GET key2\r\n", 1)?, "$-1\r\n");
+    assert_eq!(reply(b"EXISTS key1 key2\r\n", 1)?, ":1\r\n");
+    assert_eq!(reply(b"KEYS key*\r\n", 3)?, "*1\r\n$4\r\nkey1\r\n");
+    assert_eq!(reply(b"DBSIZE\r\n", 1)?, ":1\r\n");
+    assert_eq!(reply(b"DEL key1 key2\r\n", 1)?, ":1\r\n");
+    assert_eq!(reply(b"FLUSHDB\r\n", 1)?, "+OK\r\n");
+    assert_eq!(reply(b"HSET h k v\r\n", 1)?, "-ERR unknown command 'hset'\r\n");
+    assert_eq!(reply(b"QUIT\r\n", 1)?, "+OK\r\n");
+    Ok(())
+}
+
+// Sends one HTTP request and returns the status code and body of the response
+fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                    Content-Length: {}\r\n\r\n{}", method, path, body.len(), body)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response[9..12].parse()?;
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default();
+    Ok((status, body.to_owned()))
+}
+
+#[test]
+fn http_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::with_protocol(store.clone(), Protocol::Http);
+    std::thread::spawn(move || server.serve(listener));
+
+    assert_eq!(http(addr, "GET", "/health", "")?, (200, r#"{"status":"ok"}"#.to_owned()));
+    assert_eq!(http(addr, "PUT", "/keys/a%2F1", r#"{"value":"v1"}"#)?.0, 204);
+    assert_eq!(http(addr, "PUT", "/keys/a2", r#"{"value":"v2"}"#)?.0, 204);
+    assert_eq!(http(addr, "PUT", "/keys/b1", r#"{"value":"v3"}"#)?.0, 204);
+    assert_eq!(http(addr, "PUT", "/keys/b1", r#"{"val":"v3"}"#)?.0, 400);
+    assert_eq!(http(addr, "GET", "/keys/a%2F1", "")?,
+               (200, r#"{"key":"a/1","value":"v1"}"#.to_owned()));
+    assert_eq!(http(addr, "GET", "/keys?prefix=a&limit=1", "")?,
+               (200, r#"{"keys":["a/1"],"truncated":true}"#.to_owned()));
+    assert_eq!(http(addr, "GET", "/keys?prefix=a", "")?,
+               (200, r#"{"keys":["a/1","a2"],"truncated":false}"#.to_owned()));
+    // Listing reads no values, and keys outside the prefix are never looked at
+    store.set_bytes(b"a3", b"\xff")?;
+    store.set_bytes(b"b\xff", b"\xff")?;
+    assert_eq!(http(addr, "GET", "/keys?prefix=a&limit=2", "")?,
+               (200, r#"{"keys":["a/1","a2"],"truncated":true}"#.to_owned()));
+    store.remove_bytes(b"a3")?;
+    store.remove_bytes(b"b\xff")?;
+    // `+` is a literal in paths, a space in query strings
+    assert_eq!(http(addr, "PUT", "/keys/c+1", r#"{"value":"v+"}"#)?.0, 204);
+    assert_eq!(http(addr, "GET", "/keys/c+1", "")?,
+               (200, r#"{"key":"c+1","value":"v+"}"#.to_owned()));
+    assert_eq!(http(addr, "GET", "/keys?prefix=c%2B", "")?,
+               (200, r#"{"keys":["c+1"],"truncated":false}"#.to_owned()));
+    assert_eq!(http(addr, "DELETE", "/keys/c+1", "")?.0, 204);
+    assert_eq!(http(addr, "POST", "/keys/a2/cas", r#"{"expected":"v1","new":"v5"}"#)?,
+               (409, r#"{"swapped":false,"current":"v2"}"#.to_owned()));
+    assert_eq!(http(addr, "POST", "/keys/a2/cas", r#"{"expected":"v2","new":"v5"}"#)?,
+               (200, r#"{"swapped":true}"#.to_owned()));
+    assert_eq!(http(addr, "POST", "/keys/zz/cas", r#"{"expected":"v1","new":null}"#)?,
+               (409, r#"{"swapped":false,"current":null}"#.to_owned()));
+    assert_eq!(http(addr, "DELETE", "/keys/a2", "")?.0, 204);
+    assert_eq!(http(addr, "DELETE", "/keys/a2", "")?,
+               (404, r#"{"error":"Key not found"}"#.to_owned()));
+    assert_eq!(http(addr, "GET", "/keys/a2", "")?.0, 404);
+
+    let batch = r#"{"ops":[{"op":"set","key":"c1","value":"v4"},{"op":"get","key":"c1"},
+                           {"op":"delete","key":"zz"}]}"#;
+    assert_eq!(http(addr, "POST", "/batch", batch)?,
+               (200, r#"{"results":[{"ok":true},{"ok":true,"value":"v4"},{"ok":false,"error":"Key not found"}]}"#
+                   .to_owned()));
+    let batch = r#"{"ops":[{"op":"set","key":"d1","value":"v6"},{"op":"delete","key":"c1"},
+                           {"op":"get","key":"c1"},{"op":"delete","key":"c1"}]}"#;
+    assert_eq!(http(addr, "POST", "/batch", batch)?,
+               (200, r#"{"results":[{"ok":true},{"ok":true},{"ok":true},{"ok":false,"error":"Key not found"}]}"#
+                   .to_owned()));
+    assert_eq!(store.get("c1".to_owned())?, None);
+
+    let (status, stats) = http(addr, "GET", "/stats", "")?;
+    assert_eq!(status, 200);
+    assert!(stats.contains(r#""keys":3"#), "{}", stats);
+    assert_eq!(http(addr, "POST", "/health", "")?.0, 405);
+    assert_eq!(http(addr, "GET", "/nope", "")?.0, 404);
+    // A value that is not UTF-8 cannot be returned as a JSON string
+    store.set_bytes(b"bin", b"\xff")?;
+    assert_eq!(http(addr, "GET", "/keys/bin", "")?.0, 422);
+
+    // Writes to a read-only store are refused
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::with_protocol(KvStore::open_read_only(temp_dir.path())?, Protocol::Http);
+    std::thread::spawn(move || server.serve(listener));
+    assert_eq!(http(addr, "GET", "/keys/b1", "")?.0, 200);
+    assert_eq!(http(addr, "PUT", "/keys/b1", r#"{"value":"v7"}"#)?.0, 405);
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [0xffu8, 0x00, 0xfe];
+    let value = [0xc3u8, 0x28, 0x00, 0x01];
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(key, value)?;
+    store.set_bytes(b"text", b"\xff")?;
+    store.set_bytes(b"gone", b"")?;
+    store.remove_bytes(b"gone")?;
+    assert_eq!(store.get_bytes(key)?, Some(value.to_vec()));
+    drop(store);
+
+    // Replaying the log must not choke on non UTF-8 keys
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(value.to_vec()));
+    assert_eq!(store.get_bytes(b"gone")?, None);
+    assert_eq!(store.scan_bytes(..)?.len(), 2);
+    let err = store.get("text".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::InvalidUtf8)));
+    assert!(matches!(store.remove_bytes(b"gone").unwrap_err().downcast_ref::<KvError>(),
+                     Some(KvError::KeyNotFound)));
+
+    // Over the wire as well
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get_bytes(key)?, Some(value.to_vec()));
+    client.set_bytes(b"\x00", b"\x01")?;
+    assert_eq!(client.get_bytes(b"\x00")?, Some(vec![1]));
+    Ok(())
+}
+
+#[test]
+fn range_and_prefix_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["b2", "a1", "c1", "b1", "b3", "a2"] {
+        store.set(key.to_owned(), format!("v{}", key))?;
+    }
+    store.remove("b2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| String::from_utf8(key).unwrap()).collect()
+    };
+    assert_eq!(keys(store.scan(..).collect::<Result<_>>()?), ["a1", "a2", "b1", "b3", "c1"]);
+    assert_eq!(keys(store.scan(b"a2".to_vec()..b"c1".to_vec()).collect::<Result<_>>()?), ["a2", "b1", "b3"]);
+    assert_eq!(keys(store.scan_prefix("b").collect::<Result<_>>()?), ["b1", "b3"]);
+    assert_eq!(keys(store.scan_prefix("b").rev().collect::<Result<_>>()?), ["b3", "b1"]);
+    assert_eq!(keys(store.scan(..).rev().take(2).collect::<Result<_>>()?), ["c1", "b3"]);
+    assert_eq!(store.scan_prefix("a").next().transpose()?, Some((b"a1".to_vec(), b"va1".to_vec())));
+    assert_eq!(store.scan_prefix("d").count(), 0);
+
+    // Keys removed after the scan started are skipped
+    let mut scan = store.scan_prefix("a");
+    store.remove("a1".to_owned())?;
+    assert_eq!(keys(vec![scan.next().unwrap()?]), ["a2"]);
+    assert!(scan.next().is_none());
+
+    let query = ScanQuery {
+        start: Some(b"b".to_vec()),
+        prefix: Some(b"b".to_vec()),
+        reverse: true,
+        limit: Some(1),
+        ..ScanQuery::default()
+    };
+    assert_eq!(keys(query.run(&store)?), ["b3"]);
+
+    // A limited scan reads no value past its limit
+    store.set("c2".to_owned(), "vc2".to_owned())?;
+    let datafile = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .find(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .expect("no datafile written");
+    let mut bytes = std::fs::read(datafile.path())?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(datafile.path(), bytes)?;
+    let query = ScanQuery { start: Some(b"c".to_vec()), limit: Some(1), ..ScanQuery::default() };
+    assert_eq!(keys(query.run(&store)?), ["c1"]);
+    assert!(ScanQuery { limit: Some(2), ..query }.run(&store).is_err());
+    Ok(())
+}
+
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in [("b1", "v2"), ("a1", "v1"), ("b2", "v3")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a1\tv1\nb1\tv2\nb2\tv3\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "b", "--reverse", "--limit", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("b2\tv3\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "a1", "b2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a1\tv1\nb1\tv2\n"));
+}
+
+#[test]
+fn client_scan() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let engine = MemoryEngine::new();
+    for key in ["k3", "k1", "j1", "k2"] {
+        engine.set(key.to_owned(), "v".to_owned())?;
+    }
+    std::thread::spawn(move || KvsServer::new(engine).serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    let pairs = client.scan(ScanQuery { prefix: Some(b"k".to_vec()), limit: Some(2), ..ScanQuery::default() })?;
+    assert_eq!(pairs, vec![(b"k1".to_vec(), b"v".to_vec()), (b"k2".to_vec(), b"v".to_vec())]);
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2", "value2")
+        .put("key3", "value3")
+        .delete("key1")
+        .delete("missing")
+        .put("key3", "value33");
+    assert_eq!(batch.len(), 5);
+    store.write(batch)?;
+    store.write(WriteBatch::new())?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value33".to_owned()));
+        assert_eq!(store.get("missing".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A batch cut short by a crash must vanish as a whole, wherever it was cut
+#[test]
+fn uncommitted_batch_recovery() -> Result<()> {
+    // Size of a batch marker record: header with an empty key, then the u64 count
+    let marker_size = 40;
+    for cut in [4, marker_size, marker_size + 10] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.put("key1", "value11").put("key2", "value2");
+        store.write(batch)?;
+        drop(store);
+
+        let datafile = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .find(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+            .expect("no datafile written");
+        let len = std::fs::metadata(datafile.path())?.len();
+        let f = std::fs::OpenOptions::new().write(true).open(datafile.path())?;
+        f.set_len(len - cut)?;
+        drop(f);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Batches in sealed datafiles are replayed from hint files and survive compaction
+#[test]
+fn batch_across_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for round in 0..20 {
+        let mut batch = WriteBatch::new();
+        for i in 0..5 {
+            batch.put(format!("key{}", i), format!("value{}_{}", round, i));
+        }
+        store.write(batch)?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value19_{}", i)));
+    }
+    assert_eq!(store.stats()?.keys, 5);
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "10".to_owned())?;
+    let balance = store.transaction(|txn| {
+        let balance: u64 = txn.get("balance".to_owned())?.unwrap().parse()?;
+        txn.set("balance".to_owned(), (balance + 5).to_string())?;
+        // Reads see the transaction's own writes
+        assert_eq!(txn.get("balance".to_owned())?, Some((balance + 5).to_string()));
+        txn.remove("balance".to_owned())?;
+        assert!(txn.remove("balance".to_owned()).is_err());
+        txn.set("balance".to_owned(), (balance + 5).to_string())?;
+        Ok(balance + 5)
+    })?;
+    assert_eq!(balance, 15);
+    assert_eq!(store.get("balance".to_owned())?, Some("15".to_owned()));
+
+    // An error from the closure aborts without writing
+    let res: Result<()> = store.transaction(|txn| {
+        txn.set("balance".to_owned(), "0".to_owned())?;
+        Err(anyhow::anyhow!("insufficient funds"))
+    });
+    assert_eq!(res.unwrap_err().to_string(), "insufficient funds");
+    assert_eq!(store.get("balance".to_owned())?, Some("15".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("balance".to_owned())?, Some("15".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+
+    // A write to a key in the read set between the read and the commit reruns the closure
+    let mut attempts = 0;
+    store.transaction(|txn| {
+        attempts += 1;
+        let value = txn.get("key1".to_owned())?.unwrap();
+        if attempts == 1 {
+            store.set("key1".to_owned(), "2".to_owned())?;
+        }
+        txn.set("key2".to_owned(), value)
+    })?;
+    assert_eq!(attempts, 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("2".to_owned()));
+
+    // Rewriting a key that was read conflicts even if its value ends up the same,
+    // and a transaction that always conflicts gives up
+    let mut attempts = 0;
+    let res = store.transaction(|txn| {
+        attempts += 1;
+        txn.get("key3".to_owned())?;
+        store.set("key3".to_owned(), attempts.to_string())?;
+        store.remove("key3".to_owned())?;
+        store.set("key3".to_owned(), attempts.to_string())?;
+        txn.set("key4".to_owned(), "x".to_owned())
+    });
+    let err = res.unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::TransactionConflict)));
+    assert_eq!(attempts, MAX_TRANSACTION_RETRIES + 1);
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Concurrent increments through transactions never lose an update,
+// even while compaction moves values around
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: 0,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let res = store.transaction(|txn| {
+                            let n: u64 = txn.get("counter".to_owned())?
+                                .map_or(Ok(0), |n| n.parse())?;
+                            txn.set("counter".to_owned(), (n + 1).to_string())
+                        });
+                        match res {
+                            Err(e) if matches!(e.downcast_ref::<KvError>(), Some(KvError::TransactionConflict)) => {}
+                            res => break res?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set_if_absent(b"key1", b"value1")?, Ok(()));
+    assert_eq!(store.compare_and_swap(b"key1", Some(b"value1"), Some(b"value2"))?, Ok(()));
+    assert_eq!(store.compare_and_swap(b"key2", Some(b"value1"), None)?, Err(CasMismatch { current: None }));
+    assert_eq!(store.set_if_absent(b"key2", b"value3")?, Ok(()));
+    assert_eq!(store.remove_if_equals(b"key2", b"value3")?, Ok(()));
+    // Expecting absent and writing nothing is a no-op
+    assert_eq!(store.compare_and_swap(b"key3", None, None)?, Ok(()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.set_if_absent(b"key1", b"value4")?, Err(CasMismatch { current: Some(b"value2".to_vec()) }));
+    Ok(())
+}
+
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let mut current = store.get_bytes(b"counter")?;
+                    loop {
+                        let n: u64 = current.as_deref().map_or(0, |n| std::str::from_utf8(n).unwrap().parse().unwrap());
+                        match store.compare_and_swap(b"counter", current.as_deref(), Some((n + 1).to_string().as_bytes()))? {
+                            Ok(()) => break,
+                            Err(mismatch) => current = mismatch.current,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_cas() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--new", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--new", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1", "--new", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+#[test]
+fn client_compare_and_swap() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(MemoryEngine::new()).serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.compare_and_swap(b"key1", None, Some(b"value1"))?, Ok(()));
+    assert_eq!(client.compare_and_swap(b"key1", None, Some(b"value2"))?,
+               Err(CasMismatch { current: Some(b"value1".to_vec()) }));
+    assert_eq!(client.compare_and_swap(b"key1", Some(b"value1"), None)?, Ok(()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.incr_by(b"hits", 5)?;
+    store.incr_by(b"hits", -2)?;
+    assert_eq!(store.get("hits".to_owned())?, Some("3".to_owned()));
+    store.set("hits".to_owned(), "10".to_owned())?;
+    store.incr_by(b"hits", 1)?;
+    assert_eq!(store.get("hits".to_owned())?, Some("11".to_owned()));
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    store.incr_by(b"name", 1)?;
+    let err = store.get("name".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::NotAnInteger)));
+    // Compaction copies what it cannot fold
+    store.compact()?;
+    assert!(store.get("name".to_owned()).is_err());
+    assert_eq!(store.get("hits".to_owned())?, Some("11".to_owned()));
+    store.remove("name".to_owned())?;
+    assert_eq!(store.get("name".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("11".to_owned()));
+    store.incr_by(b"hits", 1)?;
+    assert_eq!(store.get("hits".to_owned())?, Some("12".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn counter_operands_are_folded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: u64::MAX,
+        compaction_max_segments: usize::MAX,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let n = 10 * MAX_MERGE_OPERANDS as i64;
+    for i in 0..n {
+        store.incr_by(b"counter", 1)?;
+        if i % 7 == 0 {
+            store.incr_by(b"other", 2)?;
+        }
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some(n.to_string()));
+    assert!(store.stats()?.datafiles > 2);
+    store.compact()?;
+    store.incr_by(b"counter", 1)?;
+    assert_eq!(store.get("counter".to_owned())?, Some((n + 1).to_string()));
+    assert_eq!(store.get("other".to_owned())?, Some((2 * ((n + 6) / 7)).to_string()));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("counter".to_owned())?, Some((n + 1).to_string()));
+    assert_eq!(store.get("other".to_owned())?, Some((2 * ((n + 6) / 7)).to_string()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: 0,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    store.incr_by(b"counter", 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let err = store.merge(b"list", b"a").unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::NoMergeOperator)));
+    drop(store);
+
+    // Appends operands to a comma separated list
+    let append = |_: &[u8], existing: Option<&[u8]>, operand: &[u8]| -> Result<Vec<u8>> {
+        Ok(match existing {
+            Some(existing) => [existing, b",", operand].concat(),
+            None => operand.to_vec(),
+        })
+    };
+    let options = Options {
+        merge_operator: Some(std::sync::Arc::new(append)),
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.merge(b"list", b"a")?;
+    store.merge(b"list", b"b")?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b".to_owned()));
+    store.set("list".to_owned(), "x".to_owned())?;
+    store.merge(b"list", b"c")?;
+    store.incr_by(b"count", 2)?;
+    assert_eq!(store.scan(..).collect::<Result<Vec<_>>>()?, vec![
+        (b"count".to_vec(), b"2".to_vec()),
+        (b"list".to_vec(), b"x,c".to_vec()),
+    ]);
+    store.compact()?;
+    assert_eq!(store.get("list".to_owned())?, Some("x,c".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.merge(b"list", b"d")?;
+    assert_eq!(store.get("list".to_owned())?, Some("x,c,d".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("session".to_owned(), "abc".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("cache".to_owned(), "def".to_owned(), Duration::from_secs(3600))?;
+    store.set("user".to_owned(), "ghi".to_owned())?;
+    assert_eq!(store.get("session".to_owned())?, Some("abc".to_owned()));
+    let ttl = store.ttl("cache".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600), "{:?}", ttl);
+    assert_eq!(store.ttl("user".to_owned())?, None);
+
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(store.get("session".to_owned())?, None);
+    let err = store.ttl("session".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)));
+    let err = store.remove("session".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)));
+    assert_eq!(store.scan(..).collect::<Result<Vec<_>>>()?, vec![
+        (b"cache".to_vec(), b"def".to_vec()),
+        (b"user".to_vec(), b"ghi".to_vec()),
+    ]);
+
+    // An expired key can be written again, and counters on it start over
+    assert_eq!(store.set_if_absent(b"session", b"jkl")?, Ok(()));
+    store.set_with_ttl("count".to_owned(), "5".to_owned(), Duration::from_millis(50))?;
+    store.incr_by(b"count", 1)?;
+    assert_eq!(store.get("count".to_owned())?, Some("6".to_owned()));
+    std::thread::sleep(Duration::from_millis(100));
+    store.incr_by(b"count", 1)?;
+    assert_eq!(store.get("count".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.ttl("count".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, Some("jkl".to_owned()));
+    assert!(store.ttl("cache".to_owned())?.is_some());
+    assert_eq!(store.get("count".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// Folding the operands of a counter into a full value should keep its expiry.
+#[test]
+fn folded_counter_keeps_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("count".to_owned(), "0".to_owned(), Duration::from_millis(500))?;
+    let n = 2 * MAX_MERGE_OPERANDS as i64;
+    for _ in 0..n {
+        store.incr_by(b"count", 1)?;
+    }
+    assert_eq!(store.get("count".to_owned())?, Some(n.to_string()));
+    assert!(store.ttl("count".to_owned())?.is_some());
+
+    std::thread::sleep(Duration::from_millis(550));
+    assert_eq!(store.get("count".to_owned())?, None);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("count".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: u64::MAX,
+        compaction_max_segments: usize::MAX,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set_with_ttl(format!("temp{}", i), "value".to_owned(), Duration::from_millis(100))?;
+        store.set_with_ttl(format!("kept{}", i), "value".to_owned(), Duration::from_secs(3600))?;
+    }
+    assert_eq!(store.stats()?.keys, 200);
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.stats()?.keys, 100);
+    store.compact()?;
+    assert_eq!(store.stats()?.keys, 100);
+    assert!(store.ttl("kept0".to_owned())?.is_some());
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?.keys, 100);
+    assert_eq!(store.get("temp0".to_owned())?, None);
+    assert!(store.ttl("kept99".to_owned())?.is_some());
+    Ok(())
+}
+
+// Keys that expire should count as dead bytes, so that a store whose keys
+// only ever expire still gets compacted in the background.
+#[test]
+fn expired_keys_trigger_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: 0,
+        compaction_max_segments: usize::MAX,
+        ..Options::default()
+    };
+    let datafiles = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+            .count()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set_with_ttl(format!("temp{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    let before = datafiles();
+    assert!(before > 4);
+    std::thread::sleep(Duration::from_millis(150));
+    // Sealing the next datafile makes the worker check the thresholds
+    for i in 0..30 {
+        store.set(format!("kept{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    assert!(datafiles() < before / 2, "{} of {} datafiles left", datafiles(), before);
+    Ok(())
+}
+
+// A compaction should drop keys that expired since the previous one, even with
+// nothing written in between.
+#[test]
+fn compaction_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_segment_size: 1024,
+        compaction_min_dead_bytes: u64::MAX,
+        compaction_max_segments: usize::MAX,
+        ..Options::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set_with_ttl(format!("temp{}", i), "value".to_owned(), Duration::from_millis(200))?;
+        store.set(format!("kept{}", i), "value".to_owned())?;
+    }
+    store.compact()?;
+    let before = store.stats()?.disk_bytes;
+    std::thread::sleep(Duration::from_millis(250));
+    store.compact()?;
+    let after = store.stats()?.disk_bytes;
+    assert!(after < before, "{} bytes on disk, {} before", after, before);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?.keys, 100);
+    assert_eq!(store.get("temp0".to_owned())?, None);
+    assert_eq!(store.get("kept99".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "1h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("3600s").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No expiry").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn client_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    std::thread::spawn(move || server.serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    client.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_secs(60))?;
+    assert!(client.ttl("key1".to_owned())?.is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+    let err = client.ttl("key2".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvError>(), Some(KvError::KeyNotFound)));
+    Ok(())
+}
+
+#[test]
+fn sync_modes() -> Result<()> {
+    let syncs = |sync: SyncMode, writes: usize| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options { sync, ..Options::default() };
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..writes {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        let syncs = store.stats()?.syncs;
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.stats()?.keys, writes as u64);
+        Ok(syncs)
+    };
+    assert_eq!(syncs(SyncMode::Never, 25)?, 0);
+    assert_eq!(syncs(SyncMode::EveryN(10), 25)?, 2);
+    assert_eq!(syncs(SyncMode::Always, 25)?, 25);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options { sync: SyncMode::Interval(10), ..Options::default() };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.stats()?.syncs, 1);
+    Ok(())
+}
+
+#[test]
+fn flush_syncs_pending_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.flush()?;
+    assert_eq!(store.stats()?.syncs, 0);
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.incr_by(b"count", 1)?;
+    store.flush()?;
+    store.flush()?;
+    assert_eq!(store.stats()?.syncs, 1);
+    Ok(())
+}
+
+// Writers waiting for an fsync at the same time should share it, and every
+// write acknowledged under `SyncMode::Always` should survive a reopen.
+#[test]
+fn group_commit() -> Result<()> {
+    const WRITERS: usize = 32;
+    const WRITES: usize = 50;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options { sync: SyncMode::Always, ..Options::default() };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(WRITERS));
+    let handles: Vec<_> = (0..WRITERS)
+        .map(|t| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..WRITES {
+                    store.set(format!("key{}_{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let writes = (WRITERS * WRITES) as u64;
+    let stats = store.stats()?;
+    assert!(stats.syncs > 0 && stats.syncs < writes / 4, "{} fsyncs for {} writes", stats.syncs, writes);
+    assert_eq!(stats.keys, writes);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for t in 0..WRITERS {
+        for i in 0..WRITES {
+            assert_eq!(store.get(format!("key{}_{}", t, i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+fn is_kv_error<T>(res: Result<T>, f: impl Fn(&KvError) -> bool) -> bool {
+    res.err().is_some_and(|e| e.downcast_ref::<KvError>().is_some_and(f))
+}
+
+#[test]
+fn builder_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .compaction_max_segments(4)
+        .sync(SyncMode::EveryN(8))
+        .index(IndexType::Hash)
+        .open()?;
+    let config = store.config();
+    assert_eq!(config.max_segment_size, 1024);
+    assert_eq!(config.compaction_max_segments, 4);
+    assert_eq!(config.sync, SyncMode::EveryN(8));
+    assert_eq!(config.index, IndexType::Hash);
+    assert!(!config.read_only);
+
+    for i in (0..100).rev() {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    let pairs: Vec<_> = store.scan_prefix(b"key04").collect::<Result<_>>()?;
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| String::from_utf8_lossy(key).into_owned()).collect();
+    assert_eq!(keys, (40..50).map(|i| format!("key{:03}", i)).collect::<Vec<_>>());
+    assert_eq!(store.get("key007".to_owned())?, Some("value7".to_owned()));
+
+    let err = KvStore::builder(temp_dir.path()).compaction_dead_ratio(2.0).open();
+    assert!(is_kv_error(err, |e| matches!(e, KvError::InvalidOptions(_))));
+    Ok(())
+}
+
+#[test]
+fn create_if_missing_and_error_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let err = KvStore::builder(&path).create_if_missing(false).open();
+    assert!(is_kv_error(err, |e| matches!(e, KvError::StoreNotFound)));
+    assert!(!path.exists());
+
+    let store = KvStore::builder(&path).error_if_exists(true).open()?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let err = KvStore::builder(&path).error_if_exists(true).open();
+    assert!(is_kv_error(err, |e| matches!(e, KvError::StoreExists)));
+    let store = KvStore::builder(&path).create_if_missing(false).open()?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_only_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = KvStore::builder(temp_dir.path()).read_only(true).open();
+    assert!(is_kv_error(err, |e| matches!(e, KvError::StoreNotFound)));
+
+    let store = KvStore::builder(temp_dir.path()).max_segment_size(1024).open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let files = |dir: &TempDir| -> Vec<_> {
+        WalkDir::new(dir.path()).into_iter()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.path().to_owned(), e.metadata().unwrap().len())
+            })
+            .collect()
+    };
+    let before = files(&temp_dir);
+
+    let store = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert!(store.config().read_only);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.scan_prefix(b"key").count(), 100);
+    let read_only = |res: Result<()>| is_kv_error(res, |e| matches!(e, KvError::ReadOnly));
+    assert!(read_only(store.set("key".to_owned(), "value".to_owned())));
+    assert!(read_only(store.remove("key42".to_owned())));
+    assert!(read_only(store.incr_by(b"counter", 1)));
+    assert!(read_only(store.compare_and_swap(b"key1", None, Some(b"value")).map(|_| ())));
+    assert!(read_only(store.write(WriteBatch::new())));
+    assert!(read_only(store.transaction(|txn| txn.set_bytes(b"key", b"value"))));
+    assert!(read_only(store.compact()));
+    store.flush()?;
+    drop(store);
+    assert_eq!(files(&temp_dir), before);
+    Ok(())
+}
+
+#[test]
+fn store_is_locked_while_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let pid = std::process::id();
+    assert!(is_kv_error(KvStore::open(temp_dir.path()),
+                        |e| matches!(e, KvError::StoreLocked { pid: Some(p) } if *p == pid)));
+
+    // Readers do not need the lock
+    let reader = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+
+    // Nor does another process get to write
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "other"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("process {}", pid)));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    // An open that finds no store leaves no lock behind
+    let empty = TempDir::new().expect("unable to create temporary working directory");
+    let err = KvStore::builder(empty.path()).create_if_missing(false).open();
+    assert!(is_kv_error(err, |e| matches!(e, KvError::StoreNotFound)));
+    assert_eq!(std::fs::read_dir(empty.path())?.count(), 0);
+    Ok(())
+}
+
+// A read-only open should leave a torn record and the permissions of a
+// read-only directory alone.
+#[test]
+fn open_read_only() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(is_kv_error(KvStore::open_read_only(temp_dir.path()), |e| matches!(e, KvError::StoreNotFound)));
+    let store = KvStore::builder(temp_dir.path()).max_segment_size(1024).open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let datafile = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "dat"))
+        .max_by_key(|e| e.path().to_owned())
+        .expect("no datafile written");
+    let len = std::fs::metadata(datafile.path())?.len();
+    std::fs::OpenOptions::new().write(true).open(datafile.path())?.set_len(len - 4)?;
+
+    let set_mode = |mode| -> Result<()> {
+        for e in WalkDir::new(temp_dir.path()).into_iter().filter_map(|e| e.ok()) {
+            let mode = if e.file_type().is_dir() { mode | 0o111 } else { mode };
+            std::fs::set_permissions(e.path(), std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    };
+    set_mode(0o444)?;
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, None);
+    assert!(is_kv_error(store.set("key".to_owned(), "value".to_owned()), |e| matches!(e, KvError::ReadOnly)));
+    drop(store);
+    set_mode(0o755)?;
+    assert_eq!(std::fs::metadata(datafile.path())?.len(), len - 4);
+    Ok(())
+}
+
+// Readers should be able to open a store while its writer keeps rolling over
+// and compacting datafiles.
+#[test]
+fn open_read_only_live_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .compaction_max_segments(2)
+        .open()?;
+    store.set("stable".to_owned(), "value".to_owned())?;
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for i in 0..2000 {
+                store.set(format!("key{}", i % 50), format!("value{}", i))?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let reader = KvStore::open_read_only(temp_dir.path())?;
+        assert_eq!(reader.get("stable".to_owned())?, Some("value".to_owned()));
+        assert!(reader.scan(..).all(|res| res.is_ok()));
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// A record as the first release wrote it to main.dat: bincode with fixed size
+// integers, without a frame, checksum or kind
+#[derive(bincode::Encode)]
+struct BaselineEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+// A store written before datafiles had a header should refuse to open until
+// `kvs upgrade` migrates it, in place, keeping every key.
+#[test]
+fn cli_upgrade() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut main_dat = Vec::new();
+    for i in 0..100 {
+        let entry = BaselineEntry { key: format!("key{}", i).into_bytes(), value: format!("value{}", i).into_bytes() };
+        main_dat.extend(bincode::encode_to_vec(&entry, bincode::config::standard().with_fixed_int_encoding())?);
+    }
+    // An empty value is how the first release recorded a removed key
+    for i in 0..10 {
+        let entry = BaselineEntry { key: format!("key{}", i).into_bytes(), value: Vec::new() };
+        main_dat.extend(bincode::encode_to_vec(&entry, bincode::config::standard().with_fixed_int_encoding())?);
+    }
+    std::fs::write(temp_dir.path().join("main.dat"), &main_dat)?;
+
+    for read_only in [false, true] {
+        let options = Options { read_only, ..Options::default() };
+        let err = KvStore::open_with(temp_dir.path(), options).err().expect("opened a headerless store");
+        assert!(matches!(err.downcast_ref::<DataFileError>(), Some(DataFileError::LegacyDatafile)));
+        assert!(err.to_string().contains("kvs upgrade"));
+    }
+    assert_eq!(std::fs::read(temp_dir.path().join("main.dat"))?, main_dat);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Upgraded 1 datafiles"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("already"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key20"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value20").trim());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            let expected = if i < 10 { None } else { Some(format!("value{}", i)) };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}